@@ -1,19 +1,50 @@
+mod emulator;
+pub use emulator::Emulator;
+mod events;
+pub use events::{Event, Events};
 mod hid;
 use hid::*;
+pub use hid::{
+    annotate_report, describe_report, read_pcap, CaptureRecord, Direction, Replay,
+    ReportAnnotation, RequestPolicy, Trace,
+};
+mod image;
+use image::*;
+pub use image::{
+    decode_profile, encode_profile, encode_profile_into, FlashImage, PROFILE_FLASH_LEN,
+};
+mod probe;
+pub use probe::{CommandProbe, FlashProbe, ProbeOutcome, ProbeReport};
 
-use crate::profile::{
-    Action, Button, Color, KeyEvent, Macro, MacroEvent, MacroMode, Profile, Resolution,
+use crate::profile::{Action, Button, KeyEvent, Profile};
+use crate::{
+    identify, BatteryStatus, Capabilities, ChargeState, DeviceInfo, Mouse, MouseIdentity, Product,
+    Transport, WriteSummary,
 };
-use crate::{identify, Mouse, Product};
 use hidapi::HidDevice;
 use keycode::{KeyMap, KeyMappingId, KeyModifiers, KeyState};
-use std::collections::HashMap;
-use std::ops::{RangeBounds, RangeInclusive};
+use std::any::Any;
+use std::ops::{Range, RangeBounds};
+use std::time::Duration;
+
+const NUM_PROFILES: usize = 4;
+
+/// Requests made to identify a mouse are only tried once, briefly, since they
+/// are made while listing devices.
+const IDENTITY_REQUEST_POLICY: RequestPolicy = RequestPolicy {
+    timeout: Duration::from_millis(250),
+    retries: 0,
+    backoff: Duration::ZERO,
+};
 
 const HID_KEYBOARD_PAGE: u16 = 0x07;
 const HID_CONSUMER_PAGE: u16 = 0x0C;
-const BATTERY_MIN_MILLIVOLTS: u16 = 3050;
-const BATTERY_MAX_MILLIVOLTS: u16 = 4200;
+
+// Raw charge states in byte 1 of the battery report. These are a best guess
+// and haven't been confirmed against the official software.
+const CHARGE_STATE_DISCHARGING: u8 = 0;
+const CHARGE_STATE_CHARGING: u8 = 1;
+const CHARGE_STATE_FULL: u8 = 2;
 
 mod address {
     pub const POLL_RATE: usize = 0;
@@ -44,6 +75,7 @@ const POLL_RATE_MAP: [(u16, u8); 7] = [
     (8000, 64),
 ];
 
+/// Buttons in the order of their action, combo and macro slots.
 const NUM_BUTTONS: usize = 6;
 const BUTTONS: [Button; NUM_BUTTONS] = [
     Button::Left,
@@ -85,559 +117,407 @@ const REMAPPED_KEYCODES: [(KeyMappingId, u16); 3] = [
     (KeyMappingId::VolumeDown, 0xEA),
 ];
 
+// Limits of the flash layout. Products may support less, which is checked
+// against their `Capabilities` instead.
 const MAX_RESOLUTION_COUNT: usize = 8;
-const MAX_RESOLUTION: u16 = 26000;
-const RANGE_LIFT_OFF_DISTANCE: RangeInclusive<usize> = 1..=2;
-const MAX_DEBOUNCE_MS: usize = 15;
 const MAX_COMBO_EVENTS: usize = 6;
 const MAX_MACRO_NAME_LEN: usize = 30;
 const MAX_MACRO_EVENTS: usize = 70;
+const COMBO_SLOT_LEN: usize = 32;
+
+/// Resolution of one raw unit. Raw resolutions are 10 bits, starting from one
+/// unit.
+const RESOLUTION_UNIT: u16 = 50;
+const MAX_RAW_RESOLUTION: u16 = 0x3FF;
+const MACRO_SLOT_LEN: usize = 384;
 
-pub struct Atlantis {
-    device: HidDevice,
+pub struct Atlantis<T: Transport = HidDevice> {
+    device: T,
     product: Product,
+    policy: RequestPolicy,
 }
 
-impl Atlantis {
+impl Atlantis<HidDevice> {
     /// Attempt to connect to an Atlantis USB HID device.
     pub fn connect(device: HidDevice) -> crate::Result<Self> {
         let product = identify(&device)?.ok_or(crate::Error::Incompatible)?;
         Ok(Self::new(device, product))
     }
+}
 
-    fn new(device: HidDevice, product: Product) -> Self {
-        Self { device, product }
-    }
-
-    fn read_flash_checked(&self, address: usize, length: usize) -> crate::Result<Vec<u8>> {
-        let mut data = read_flash(&self.device, address, length + 1)?;
-        if checksum(&data) == 0 {
-            data.pop();
-            Ok(data)
-        } else {
-            Err(crate::Error::InvalidProfile(format!(
-                "Checksum mismatch at {address:40X}: {data:?}"
-            )))
-        }
-    }
-
-    fn write_flash_checked(&self, address: usize, data: &[u8]) -> crate::Result<()> {
-        let mut data = data.to_vec();
-        data.push(checksum(&data));
-        write_flash(&self.device, address, data)
-    }
-
-    fn read_byte(&self, address: usize) -> crate::Result<u8> {
-        Ok(self.read_flash_checked(address, 1)?[0])
-    }
-
-    fn write_byte(&self, address: usize, val: u8) -> crate::Result<()> {
-        self.write_flash_checked(address, &[val])
-    }
-
-    fn read_bool(&self, address: usize) -> crate::Result<bool> {
-        Ok(self.read_byte(address)? != 0)
-    }
-
-    fn write_bool(&self, address: usize, val: bool) -> crate::Result<()> {
-        self.write_byte(address, val as u8)
-    }
+impl<T: Transport> Atlantis<T> {
+    /// Use an already identified product over any report transport.
+    pub fn new(device: T, product: Product) -> Self {
+        Self {
+            device,
+            product,
+            policy: RequestPolicy::default(),
+        }
+    }
+
+    /// Use custom timeouts and retries for requests to the mouse.
+    pub fn with_request_policy(mut self, policy: RequestPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Reads the parts of the active profile's flash that are in use.
+    ///
+    /// Each settings region is read in one sequential sweep, followed by only
+    /// the combo and macro slots that the buttons reference.
+    fn read_profile_image(&self) -> crate::Result<FlashImage> {
+        let mut image = self.read_settings()?;
+
+        for i in 0..NUM_BUTTONS {
+            match image.as_bytes()[address::BUTTON_ACTIONS + (i * 4)] {
+                // Combo
+                5 => {
+                    self.read_into_image(
+                        &mut image,
+                        address::COMBOS + (i * COMBO_SLOT_LEN),
+                        COMBO_LEN,
+                    )?;
+                }
 
-    fn poll_rate(&self) -> crate::Result<u16> {
-        let raw = self.read_byte(address::POLL_RATE)?;
-        Ok(POLL_RATE_MAP
-            .iter()
-            .find(|(_, r)| *r == raw)
-            .ok_or(crate::Error::InvalidProfile(format!(
-                "Invalid poll rate value '{}' from mouse",
-                raw
-            )))?
-            .0)
-    }
+                // Macro
+                6 => {
+                    let address = address::MACROS + (i * MACRO_SLOT_LEN);
+                    self.read_into_image(&mut image, address, MACRO_HEADER_LEN)?;
+                    let events_len = (image.as_bytes()[address + MACRO_HEADER_LEN - 1] as usize)
+                        .min(MAX_MACRO_EVENTS);
+                    self.read_into_image(
+                        &mut image,
+                        address + MACRO_HEADER_LEN,
+                        (events_len * 5) + 1,
+                    )?;
+                }
 
-    fn set_poll_rate(&self, mut poll_rate: u16) -> crate::Result<()> {
-        if poll_rate > self.product.max_poll_rate() {
-            eprintln!(
-                "Warning: Desired poll rate is unsupported by mouse. Reducing to {}Hz.",
-                self.product.max_poll_rate()
-            );
-            poll_rate = self.product.max_poll_rate();
+                _ => {}
+            }
         }
-        let raw = POLL_RATE_MAP
-            .iter()
-            .find(|(p, _)| *p == poll_rate)
-            .ok_or(crate::Error::InvalidProfile(format!(
-                "Poll rate {poll_rate} is not supported",
-            )))?
-            .1;
-        self.write_byte(address::POLL_RATE, raw)
-    }
 
-    fn resolution_index(&self) -> crate::Result<u8> {
-        let raw = self.read_byte(address::RESOLUTION_INDEX)?;
-        assert_range(0..MAX_RESOLUTION_COUNT, raw)?;
-        Ok(raw)
+        Ok(image)
     }
 
-    fn set_resolution_index(&self, resolution_index: u8) -> crate::Result<()> {
-        assert_range(0..MAX_RESOLUTION_COUNT, resolution_index)?;
-        self.write_byte(address::RESOLUTION_INDEX, resolution_index)
-    }
-
-    fn resolution_count(&self) -> crate::Result<u8> {
-        let raw = self.read_byte(address::RESOLUTION_COUNT)?;
-        assert_range(1..=MAX_RESOLUTION_COUNT, raw)?;
-        Ok(raw)
-    }
-
-    fn set_resolution_count(&self, count: u8) -> crate::Result<()> {
-        assert_range(1..=MAX_RESOLUTION_COUNT, count)?;
-        self.write_byte(address::RESOLUTION_COUNT, count)
-    }
-
-    fn resolution(&self, index: usize) -> crate::Result<Resolution> {
-        assert_range(0..MAX_RESOLUTION_COUNT, index)?;
-        let resolution_raw = self.read_flash_checked(address::RESOLUTIONS + (index * 4), 3)?;
-        let resolution = Resolution::new(
-            resolution_from_raw(resolution_raw[0]),
-            resolution_from_raw(resolution_raw[1]),
+    fn read_into_image(
+        &self,
+        image: &mut FlashImage,
+        address: usize,
+        length: usize,
+    ) -> crate::Result<()> {
+        image.fill(
+            address,
+            &read_flash(&self.device, &self.policy, address, length)?,
         );
-        assert_range(50..=MAX_RESOLUTION, resolution.x)?;
-        assert_range(50..=MAX_RESOLUTION, resolution.y)?;
-        Ok(resolution)
-    }
-
-    fn set_resolution(&self, index: usize, resolution: &Resolution) -> crate::Result<()> {
-        assert_range(0..MAX_RESOLUTION_COUNT, index)?;
-        assert_range(50..=MAX_RESOLUTION, resolution.x)?;
-        assert_range(50..=MAX_RESOLUTION, resolution.y)?;
-        self.write_flash_checked(
-            address::RESOLUTIONS + (index * 4),
-            &[
-                resolution_to_raw(resolution.x),
-                resolution_to_raw(resolution.y),
-                0,
-            ],
-        )
-    }
-
-    fn resolutions(&self) -> crate::Result<Vec<Resolution>> {
-        let count = self.resolution_count()? as usize;
-        let mut resolutions = Vec::new();
-        for i in 0..count {
-            resolutions.push(self.resolution(i)?);
-        }
-        Ok(resolutions)
-    }
-
-    fn set_resolutions(&self, resolutions: &[Resolution]) -> crate::Result<()> {
-        self.set_resolution_count(resolutions.len() as u8)?;
-        for (i, resolution) in resolutions.iter().enumerate() {
-            self.set_resolution(i, resolution)?;
-        }
         Ok(())
     }
 
-    fn resolution_color(&self, index: usize) -> crate::Result<Color> {
-        assert_range(0..MAX_RESOLUTION_COUNT, index)?;
-        let raw = self.read_flash_checked(address::RESOLUTION_COLORS + (index * 4), 3)?;
-        Ok(Color::new(raw[0], raw[1], raw[2]))
+    /// Reads the whole flash region of a profile, including bytes that aren't
+    /// mapped to any known setting.
+    pub fn read_flash_image(&self, index: usize) -> crate::Result<FlashImage> {
+        self.with_active_profile(index, || {
+            let mut image = FlashImage::new();
+            self.read_into_image(&mut image, 0, PROFILE_FLASH_LEN)?;
+            Ok(image)
+        })
     }
 
-    fn set_resolution_color(&self, index: usize, color: &Color) -> crate::Result<()> {
-        assert_range(0..MAX_RESOLUTION_COUNT, index)?;
-        self.write_flash_checked(
-            address::RESOLUTION_COLORS + (index * 4),
-            &[color.red, color.green, color.blue],
-        )
+    /// Writes a whole flash image to a profile, e.g. to restore a backup from
+    /// [`read_flash_image`](Self::read_flash_image). Only bytes that differ
+    /// from the current flash contents are written.
+    pub fn write_flash_image(
+        &self,
+        index: usize,
+        image: &FlashImage,
+    ) -> crate::Result<WriteSummary> {
+        self.with_active_profile(index, || {
+            let whole_image = 0..PROFILE_FLASH_LEN;
+            self.write_changes(image, &[whole_image])
+        })
     }
 
-    fn resolution_colors(&self) -> crate::Result<Vec<Color>> {
-        let count = self.resolution_count()? as usize;
-        let mut colors = Vec::new();
-        for i in 0..count {
-            colors.push(self.resolution_color(i)?);
+    /// Writes the bytes within `regions` of the active profile that differ
+    /// between its current flash contents and `target`.
+    fn write_changes(
+        &self,
+        target: &FlashImage,
+        regions: &[Range<usize>],
+    ) -> crate::Result<WriteSummary> {
+        let mut current = FlashImage::new();
+        for region in regions {
+            self.read_into_image(&mut current, region.start, region.len())?;
+        }
+
+        let mut summary = WriteSummary::default();
+        for range in changed_ranges(&current, target, regions) {
+            summary.bytes += range.len();
+            summary.reports += range.len().div_ceil(MAX_PAYLOAD_LEN);
+            write_flash(
+                &self.device,
+                &self.policy,
+                range.start,
+                target.as_bytes()[range].to_vec(),
+            )?;
+        }
+        Ok(summary)
+    }
+
+    /// Reads the settings and button actions of the active profile.
+    fn read_settings(&self) -> crate::Result<FlashImage> {
+        let mut image = FlashImage::new();
+        for region in SETTINGS_REGIONS {
+            self.read_into_image(&mut image, region.start, region.len())?;
+        }
+        Ok(image)
+    }
+
+    /// Runs `f` with `index` as the active profile, then switches back to the
+    /// original profile even if `f` fails.
+    ///
+    /// Only the active profile's flash can be accessed.
+    fn with_active_profile<R>(
+        &self,
+        index: usize,
+        f: impl FnOnce() -> crate::Result<R>,
+    ) -> crate::Result<R> {
+        let active_profile = self.active_profile()?;
+        if active_profile != index {
+            self.set_active_profile(index)?;
         }
-        Ok(colors)
-    }
-
-    fn set_resolution_colors(&self, colors: &[Color]) -> crate::Result<()> {
-        assert_range(1..=MAX_RESOLUTION_COUNT, colors.len())?;
-        for (i, color) in colors.iter().enumerate() {
-            self.set_resolution_color(i, color)?;
+        let result = f();
+        if active_profile != index {
+            // Report the original error rather than a failure to switch back.
+            let restored = self.set_active_profile(active_profile);
+            if result.is_ok() {
+                restored?;
+            }
         }
-        Ok(())
-    }
-
-    fn lift_off_distance(&self) -> crate::Result<u8> {
-        let raw = self.read_byte(address::LIFT_OFF_DISTANCE)?;
-        assert_range(RANGE_LIFT_OFF_DISTANCE, raw)?;
-        Ok(raw)
-    }
-
-    fn set_lift_off_distance(&self, lod: u8) -> crate::Result<()> {
-        assert_range(RANGE_LIFT_OFF_DISTANCE, lod)?;
-        self.write_byte(address::LIFT_OFF_DISTANCE, lod)?;
-        Ok(())
-    }
-
-    fn debounce_ms(&self) -> crate::Result<u8> {
-        let debounce_ms = self.read_byte(address::DEBOUNCE_MS)?;
-        assert_range(0..=MAX_DEBOUNCE_MS, debounce_ms)?;
-        Ok(debounce_ms)
-    }
-
-    fn set_debounce_ms(&self, debounce_ms: u8) -> crate::Result<()> {
-        assert_range(0..=MAX_DEBOUNCE_MS, debounce_ms)?;
-        self.write_byte(address::DEBOUNCE_MS, debounce_ms)
-    }
-
-    fn motion_sync(&self) -> crate::Result<bool> {
-        self.read_bool(address::MOTION_SYNC)
-    }
-
-    fn set_motion_sync(&self, motion_sync: bool) -> crate::Result<()> {
-        self.write_bool(address::MOTION_SYNC, motion_sync)
-    }
-
-    fn angle_snapping(&self) -> crate::Result<bool> {
-        self.read_bool(address::ANGLE_SNAPPING)
-    }
-
-    fn set_angle_snapping(&self, angle_snapping: bool) -> crate::Result<()> {
-        self.write_bool(address::ANGLE_SNAPPING, angle_snapping)
-    }
-
-    fn ripple_control(&self) -> crate::Result<bool> {
-        self.read_bool(address::RIPPLE_CONTROL)
-    }
-
-    fn set_ripple_control(&self, ripple_control: bool) -> crate::Result<()> {
-        self.write_bool(address::RIPPLE_CONTROL, ripple_control)
+        result
     }
+}
 
-    fn peak_performance(&self) -> crate::Result<bool> {
-        self.read_bool(address::PEAK_PERFORMANCE)
+impl<T: Transport> Mouse for Atlantis<T> {
+    fn product(&self) -> Product {
+        self.product
     }
 
-    fn set_peak_performance(&self, peak_performance: bool) -> crate::Result<()> {
-        self.write_bool(address::PEAK_PERFORMANCE, peak_performance)
+    fn as_any(&self) -> &dyn Any
+    where
+        Self: 'static,
+    {
+        self
     }
 
-    fn peak_performance_seconds(&self) -> crate::Result<u16> {
-        let raw = self.read_byte(address::PEAK_PERFORMANCE_TIME)?;
-        Ok(raw as u16 * 10)
+    fn num_profiles(&self) -> usize {
+        NUM_PROFILES
     }
 
-    fn set_peak_performance_seconds(&self, peak_performance_seconds: u16) -> crate::Result<()> {
-        let raw = (peak_performance_seconds / 10).min(u8::MAX as u16) as u8;
-        self.write_byte(address::PEAK_PERFORMANCE_TIME, raw)
+    fn profile(&self, index: usize) -> crate::Result<Profile> {
+        let image = self.with_active_profile(index, || self.read_profile_image())?;
+        decode_profile(&image, self.product)
     }
 
-    fn high_performance(&self) -> crate::Result<bool> {
-        self.read_bool(address::HIGH_PERFORMANCE)
-    }
+    fn set_profile(&self, index: usize, profile: &Profile) -> crate::Result<WriteSummary> {
+        // Encode first so invalid profiles are rejected before touching the mouse.
+        // Only bytes that differ from the current flash contents are written.
+        let mut target = FlashImage::new();
+        let regions = encode_profile_into(profile, self.product, &mut target)?;
 
-    fn set_high_performance(&self, high_performance: bool) -> crate::Result<()> {
-        self.write_bool(address::HIGH_PERFORMANCE, high_performance)
+        self.with_active_profile(index, || self.write_changes(&target, &regions))
     }
 
-    fn button_mappings(&self) -> crate::Result<(HashMap<Button, Action>, HashMap<String, Macro>)> {
-        let mut button_map = HashMap::new();
-        let mut macros = HashMap::new();
-        for (i, button) in BUTTONS.iter().enumerate() {
-            let action_raw = self.read_flash_checked(address::BUTTON_ACTIONS + (i * 4), 3)?;
-            match &action_raw[..] {
-                [5, 0, 0] => {
-                    button_map.insert(
-                        *button,
-                        Action::Combo {
-                            events: self.key_combo(i)?,
-                        },
-                    );
-                }
-
-                [6, macro_index, repeat] => {
-                    if *macro_index as usize != i {
-                        eprintln!("Macro index ({macro_index}) doesn't match button ({i}). Corrupted action?");
-                    }
-                    let mode = match repeat {
-                        255 => MacroMode::UntilPress,
-                        254 => MacroMode::Hold,
-                        253 => MacroMode::Toggle,
-                        x => MacroMode::Repeat(*x),
-                    };
-                    let (name, events) = self.get_macro(i)?;
-                    macros.insert(name.clone(), Macro { mode, events });
-                    button_map.insert(*button, Action::Macro { name });
-                }
-
-                _ => {
-                    button_map.insert(*button, action_from_raw(&action_raw)?);
-                }
-            }
-        }
-        Ok((button_map, macros))
+    fn reset_profile(&self, index: usize) -> crate::Result<WriteSummary> {
+        // There is no known reset command, so write the default settings.
+        self.set_profile(index, &default_profile(self.product)?)
     }
 
-    fn set_button_mappings(
-        &self,
-        button_map: &HashMap<Button, Action>,
-        macros: &HashMap<String, Macro>,
-    ) -> crate::Result<()> {
-        for (i, button) in BUTTONS.iter().enumerate() {
-            if let Some(action) = button_map.get(button) {
-                match action {
-                    Action::Combo { events } => {
-                        self.set_key_combo(i, events)?;
-                    }
-                    Action::Macro { name } => {
-                        let m = macros
-                            .get(name)
-                            .ok_or(crate::Error::InvalidProfile(format!(
-                                "Undefined reference to macro: {name}"
-                            )))?;
-                        self.set_macro(i, name, &m.events)?;
-                        self.write_flash_checked(
-                            address::BUTTON_ACTIONS + (i * 4),
-                            &[
-                                6,
-                                i as u8,
-                                match m.mode {
-                                    MacroMode::Repeat(x) => x,
-                                    MacroMode::Toggle => 253,
-                                    MacroMode::Hold => 254,
-                                    MacroMode::UntilPress => 255,
-                                },
-                            ],
-                        )?;
-                        continue;
-                    }
-                    _ => {}
-                }
-                self.write_flash_checked(
-                    address::BUTTON_ACTIONS + (i * 4),
-                    &action_to_raw(action),
-                )?;
-            }
-        }
-        Ok(())
+    fn active_profile(&self) -> crate::Result<usize> {
+        read_active_profile(&self.device, &self.policy).map(|p| p as usize)
     }
 
-    fn key_combo(&self, index: usize) -> crate::Result<Vec<KeyEvent>> {
-        assert_range(0..NUM_BUTTONS, index)?;
-        let address = address::COMBOS + (index * 32);
-        let len = read_flash(&self.device, address, 1)?[0] as usize;
-        assert_range(1..=MAX_COMBO_EVENTS, len)?;
-        let data = self.read_flash_checked(address, (len * 3) + 1)?;
-        let mut key_events = Vec::new();
-        for i in 0..len {
-            let start_byte = 1 + (i * 3);
-            key_events.push(key_event_from_raw(&data[start_byte..(start_byte + 3)])?);
+    fn set_active_profile(&self, profile: usize) -> crate::Result<()> {
+        if profile < NUM_PROFILES {
+            write_active_profile(&self.device, &self.policy, profile as u8)
+        } else {
+            Err(crate::Error::InvalidProfile(format!(
+                "Profile index '{}' is out of range (0-{})",
+                profile,
+                NUM_PROFILES - 1
+            )))
         }
-        Ok(key_events)
     }
 
-    fn set_key_combo(&self, index: usize, key_events: &[KeyEvent]) -> crate::Result<()> {
-        assert_range(0..NUM_BUTTONS, index)?;
-        assert_range(1..=MAX_COMBO_EVENTS, key_events.len())?;
-        let mut data = vec![key_events.len() as u8];
-        for key_event in key_events {
-            data.extend_from_slice(&key_event_to_raw(key_event)?);
-        }
-        self.write_flash_checked(address::COMBOS + (index * 32), &data)
+    fn battery_status(&self) -> crate::Result<BatteryStatus> {
+        let payload = read_battery(&self.device, &self.policy)?;
+        let millivolts = u16::from_be_bytes([payload[2], payload[3]]);
+        Ok(BatteryStatus {
+            millivolts,
+            percentage: battery_percentage(&self.capabilities(), millivolts),
+            charge_state: charge_state_from_raw(payload[1]),
+        })
     }
 
-    fn get_macro(&self, index: usize) -> crate::Result<(String, Vec<MacroEvent>)> {
-        assert_range(0..NUM_BUTTONS, index)?;
-
-        let mut address = address::MACROS + (index * 384);
-        let name_len = read_flash(&self.device, address, 1)?[0] as usize;
-        assert_range(1..=MAX_MACRO_NAME_LEN, name_len)?;
-        address += 1;
-
-        let name =
-            String::from_utf8_lossy(&read_flash(&self.device, address, name_len)?).to_string();
-        address += MAX_MACRO_NAME_LEN;
-
-        let events_len = read_flash(&self.device, address, 1)?[0] as usize;
-        assert_range(1..=MAX_MACRO_EVENTS, events_len)?;
-        let events_bytes = self.read_flash_checked(address, (events_len * 5) + 1)?;
-
-        let mut events = Vec::new();
-        for i in (1..events_bytes.len()).step_by(5) {
-            let key_event = key_event_from_raw(&events_bytes[i..(i + 3)])?;
-            let delay_ms = u16::from_be_bytes([events_bytes[i + 3], events_bytes[i + 4]]);
-            events.push(MacroEvent {
-                key_event,
-                delay_ms,
-            });
-        }
-
-        Ok((name, events))
+    fn identity(&self) -> crate::Result<MouseIdentity> {
+        let serial_number = self.device.usb_info()?.and_then(|info| info.serial_number);
+        let quick =
+            Atlantis::new(&self.device, self.product).with_request_policy(IDENTITY_REQUEST_POLICY);
+        let read_settings = || -> crate::Result<Vec<u8>> {
+            let mut settings = vec![quick.active_profile()? as u8];
+            settings.extend(quick.read_settings()?.into_bytes());
+            Ok(settings)
+        };
+        let settings = match read_settings() {
+            Ok(settings) => Some(settings),
+            Err(_) if serial_number.is_some() => None,
+            Err(e) => return Err(e),
+        };
+        Ok(MouseIdentity {
+            connection: self.product.connection(),
+            serial_number,
+            settings,
+        })
     }
 
-    fn set_macro(
-        &self,
-        index: usize,
-        name: &str,
-        macro_events: &[MacroEvent],
-    ) -> crate::Result<()> {
-        assert_range(0..NUM_BUTTONS, index)?;
-        let mut address = address::MACROS + (index * 384);
-
-        let mut buf = vec![0];
-        buf.extend(name.as_bytes());
-        buf[0] = buf.len() as u8 - 1;
-        assert_range(1..=MAX_MACRO_NAME_LEN, buf[0])?;
-        write_flash(&self.device, address, buf)?;
-        address += 31;
-
-        assert_range(1..MAX_MACRO_EVENTS, macro_events.len())?;
-        let mut buf = vec![macro_events.len() as u8];
-        for event in macro_events {
-            buf.extend(key_event_to_raw(&event.key_event)?);
-            buf.extend(u16::to_be_bytes(event.delay_ms));
-        }
-        buf.push(checksum(&buf));
+    fn device_info(&self) -> crate::Result<DeviceInfo> {
+        let Some(usb_info) = self.device.usb_info()? else {
+            return Ok(DeviceInfo::default());
+        };
 
-        write_flash(&self.device, address, buf)
+        // There is no known command for the mouse's own firmware version, so
+        // it's only known when the mouse is plugged in directly.
+        let release = Some(usb_info.release());
+        let (firmware_version, receiver_firmware_version) = match self.product {
+            Product::Unknown => (None, None),
+            product if product.is_receiver() => (None, release.clone()),
+            _ => (release.clone(), None),
+        };
+        Ok(DeviceInfo {
+            manufacturer: usb_info.manufacturer,
+            product_name: usb_info.product,
+            serial_number: usb_info.serial_number,
+            usb_release: release,
+            firmware_version,
+            receiver_firmware_version,
+        })
     }
 }
 
-impl Mouse for Atlantis {
-    const NUM_PROFILES: usize = 4;
-
-    fn profile(&self, index: usize) -> crate::Result<Profile> {
-        // Only the active profile can be accessed, so store the current profile and
-        // switch.
-        let active_profile = self.active_profile()?;
-        if active_profile != index {
-            self.set_active_profile(index)?;
-        }
-
-        let (button_map, macros) = self.button_mappings()?;
-        let profile = Profile {
-            poll_rate: Some(self.poll_rate()?),
-            current_resolution_index: Some(self.resolution_index()? as usize),
-            resolutions: self.resolutions()?,
-            resolution_colors: self.resolution_colors()?,
-            lift_off_distance: Some(self.lift_off_distance()?),
-            debounce_ms: Some(self.debounce_ms()?),
-            motion_sync: Some(self.motion_sync()?),
-            angle_snapping: Some(self.angle_snapping()?),
-            ripple_control: Some(self.ripple_control()?),
-            peak_performance: Some(self.peak_performance()?),
-            peak_performance_time: Some(self.peak_performance_seconds()?),
-            high_performance: Some(self.high_performance()?),
-            button_map,
-            macros,
-        };
-
-        // Switch back to original profile.
-        if active_profile != index {
-            self.set_active_profile(active_profile)?;
-        }
+/// Opens an Atlantis driver for an identified device, for use as a
+/// product's driver.
+pub(crate) fn open_driver(
+    transport: Box<dyn Transport>,
+    product: Product,
+    policy: RequestPolicy,
+) -> Box<dyn Mouse> {
+    Box::new(Atlantis::new(transport, product).with_request_policy(policy))
+}
 
-        Ok(profile)
+/// Estimates the charge percentage from the battery voltage by interpolating
+/// the product's discharge curve.
+fn battery_percentage(capabilities: &Capabilities, millivolts: u16) -> u8 {
+    let curve = capabilities.discharge_curve;
+    let upper = curve.partition_point(|(mv, _)| *mv < millivolts);
+    if upper == 0 {
+        return curve.first().map_or(0, |(_, percentage)| *percentage);
     }
+    let Some(&(high_mv, high_percent)) = curve.get(upper) else {
+        return curve[curve.len() - 1].1;
+    };
+    let (low_mv, low_percent) = curve[upper - 1];
+    let fraction = (millivolts - low_mv) as f32 / (high_mv - low_mv) as f32;
+    (low_percent as f32 + fraction * (high_percent - low_percent) as f32).round() as u8
+}
 
-    fn set_profile(&self, index: usize, profile: &Profile) -> crate::Result<()> {
-        // Only the active profile can be accessed, so store the current profile and
-        // switch.
-        let active_profile = self.active_profile()?;
-        if active_profile != index {
-            self.set_active_profile(index)?;
-        }
-
-        if let Some(val) = profile.poll_rate {
-            self.set_poll_rate(val)?;
-        }
-        if let Some(val) = profile.current_resolution_index {
-            self.set_resolution_index(val as u8)?;
-        }
-        if !profile.resolutions.is_empty() {
-            self.set_resolutions(&profile.resolutions)?;
-        }
-        if !profile.resolution_colors.is_empty() {
-            self.set_resolution_colors(&profile.resolution_colors)?;
-        }
-        if let Some(val) = profile.lift_off_distance {
-            self.set_lift_off_distance(val as u8)?;
-        }
-        if let Some(val) = profile.debounce_ms {
-            self.set_debounce_ms(val as u8)?;
-        }
-        if let Some(val) = profile.motion_sync {
-            self.set_motion_sync(val)?;
-        }
-        if let Some(val) = profile.angle_snapping {
-            self.set_angle_snapping(val)?;
-        }
-        if let Some(val) = profile.ripple_control {
-            self.set_ripple_control(val)?;
-        }
-        if let Some(val) = profile.peak_performance {
-            self.set_peak_performance(val)?;
-        }
-        if let Some(val) = profile.peak_performance_time {
-            self.set_peak_performance_seconds(val)?;
-        }
-        if let Some(val) = profile.high_performance {
-            self.set_high_performance(val)?;
-        }
-        if !profile.button_map.is_empty() {
-            self.set_button_mappings(&profile.button_map, &profile.macros)?;
-        }
-
-        // Switch back to original profile.
-        if active_profile != index {
-            self.set_active_profile(active_profile)?;
-        }
-
-        Ok(())
+fn charge_state_from_raw(raw: u8) -> ChargeState {
+    match raw {
+        CHARGE_STATE_DISCHARGING => ChargeState::Discharging,
+        CHARGE_STATE_CHARGING => ChargeState::Charging,
+        CHARGE_STATE_FULL => ChargeState::Full,
+        raw => ChargeState::Unknown(raw),
     }
+}
 
-    fn active_profile(&self) -> crate::Result<usize> {
-        read_active_profile(&self.device).map(|p| p as usize)
+fn charge_state_to_raw(state: ChargeState) -> u8 {
+    match state {
+        ChargeState::Discharging => CHARGE_STATE_DISCHARGING,
+        ChargeState::Charging => CHARGE_STATE_CHARGING,
+        ChargeState::Full => CHARGE_STATE_FULL,
+        ChargeState::Unknown(raw) => raw,
     }
+}
 
-    fn set_active_profile(&self, profile: usize) -> crate::Result<()> {
-        if profile < Self::NUM_PROFILES {
-            write_active_profile(&self.device, profile as u8)
-        } else {
-            Err(crate::Error::InvalidProfile(format!(
-                "Profile index '{}' is out of range (0-3)",
-                profile
-            )))
-        }
-    }
+/// Returns the default settings for a product, used to reset profiles.
+///
+/// These are chosen by this tool rather than copied from a factory-fresh
+/// mouse. Every product shares them, minus any settings the product doesn't
+/// support.
+pub fn default_profile(product: Product) -> crate::Result<Profile> {
+    let mut profile: Profile = ron::de::from_str(include_str!("atlantis/default_profile.ron"))
+        .map_err(|e| crate::Error::InvalidProfile(format!("Default profile is invalid: {e}")))?;
+    product.capabilities().strip_unsupported(&mut profile);
+    Ok(profile)
+}
 
-    fn battery_voltage(&self) -> crate::Result<u16> {
-        read_battery_voltage(&self.device)
-    }
+/// Converts a resolution into its raw 10 bit value, rejecting resolutions that
+/// the product doesn't support.
+fn resolution_to_raw(capabilities: &Capabilities, resolution: u16) -> crate::Result<u16> {
+    assert_resolution(capabilities, resolution)?;
+    let units = resolution / RESOLUTION_UNIT;
+    if !resolution.is_multiple_of(RESOLUTION_UNIT) || !(1..=MAX_RAW_RESOLUTION + 1).contains(&units)
+    {
+        return Err(crate::Error::InvalidProfile(format!(
+            "Resolution '{resolution}' can't be encoded"
+        )));
+    }
+    Ok(units - 1)
+}
 
-    fn battery_percentage(&self) -> crate::Result<u8> {
-        let battery_mv = self.battery_voltage()?;
+fn resolution_from_raw(capabilities: &Capabilities, raw: u16) -> crate::Result<u16> {
+    let resolution = (raw + 1) * RESOLUTION_UNIT;
+    assert_resolution(capabilities, resolution)?;
+    Ok(resolution)
+}
 
-        // Basic linear battery percentage calculation.
-        let voltage_range = (BATTERY_MAX_MILLIVOLTS - BATTERY_MIN_MILLIVOLTS) as f32;
-        let battery_percent =
-            battery_mv.saturating_sub(BATTERY_MIN_MILLIVOLTS) as f32 / voltage_range * 100.0;
-        Ok((battery_percent.round() as u8).min(100))
-    }
+fn assert_resolution(capabilities: &Capabilities, resolution: u16) -> crate::Result<()> {
+    assert_range(
+        capabilities.min_resolution..=capabilities.max_resolution,
+        resolution,
+    )?;
+    if !resolution.is_multiple_of(capabilities.resolution_step) {
+        return Err(crate::Error::InvalidProfile(format!(
+            "Resolution '{resolution}' is not a multiple of {}",
+            capabilities.resolution_step
+        )));
+    }
+    Ok(())
 }
 
-fn resolution_to_raw(resolution: u16) -> u8 {
-    (resolution / 50).saturating_sub(1) as u8
+/// Packs raw X and Y resolutions into 3 bytes.
+///
+/// The low 8 bits of each go in the first two bytes. Resolutions above 12800
+/// need more than 8 bits, so the upper 2 bits of X and Y are stored in bits 2-3
+/// and 6-7 of the third byte.
+fn resolution_pair_to_bytes(x: u16, y: u16) -> [u8; 3] {
+    let [x_high, x_low] = x.to_be_bytes();
+    let [y_high, y_low] = y.to_be_bytes();
+    [
+        x_low,
+        y_low,
+        ((x_high & 0b11) << 2) | ((y_high & 0b11) << 6),
+    ]
 }
 
-fn resolution_from_raw(raw: u8) -> u16 {
-    (raw as u16 + 1) * 50
+fn resolution_pair_from_bytes(raw: &[u8]) -> (u16, u16) {
+    let x_high = (raw[2] >> 2) & 0b11;
+    let y_high = (raw[2] >> 6) & 0b11;
+    (
+        u16::from_be_bytes([x_high, raw[0]]),
+        u16::from_be_bytes([y_high, raw[1]]),
+    )
 }
 
-fn action_to_raw(action: &Action) -> [u8; 3] {
-    match action {
+fn action_to_raw(capabilities: &Capabilities, action: &Action) -> crate::Result<[u8; 3]> {
+    Ok(match action {
         Action::Disabled => [0, 0, 0],
 
         Action::LeftClick => [1, 1, 0],
@@ -649,7 +529,12 @@ fn action_to_raw(action: &Action) -> [u8; 3] {
         Action::ResolutionLoop => [2, 1, 0],
         Action::ResolutionUp => [2, 2, 0],
         Action::ResolutionDown => [2, 3, 0],
-        Action::ResolutionLock { resolution } => [10, resolution_to_raw(*resolution), 0],
+        Action::ResolutionLock { resolution } => {
+            // Same layout as a resolution slot, with X and Y the same.
+            let raw = resolution_to_raw(capabilities, *resolution)?;
+            let [low, _, high] = resolution_pair_to_bytes(raw, raw);
+            [10, low, high]
+        }
 
         Action::PollRateLoop => [7, 0, 0],
 
@@ -661,11 +546,15 @@ fn action_to_raw(action: &Action) -> [u8; 3] {
         Action::Fire { interval, repeat } => [4, *interval, *repeat],
 
         Action::Combo { .. } => [5, 0, 0],
-        Action::Macro { .. } => unimplemented!("Macro actions should be converted manually."),
-    }
+        Action::Macro { .. } => {
+            return Err(crate::Error::InvalidProfile(
+                "Macro actions must be encoded along with their macro".to_string(),
+            ))
+        }
+    })
 }
 
-fn action_from_raw(raw: &[u8]) -> crate::Result<Action> {
+fn action_from_raw(capabilities: &Capabilities, raw: &[u8]) -> crate::Result<Action> {
     Ok(match raw {
         [0, 0, 0] => Action::Disabled,
 
@@ -678,9 +567,12 @@ fn action_from_raw(raw: &[u8]) -> crate::Result<Action> {
         [2, 1, 0] => Action::ResolutionLoop,
         [2, 2, 0] => Action::ResolutionUp,
         [2, 3, 0] => Action::ResolutionDown,
-        [10, raw_resolution, 0] => Action::ResolutionLock {
-            resolution: resolution_from_raw(*raw_resolution),
-        },
+        [10, low, high] => {
+            let (raw, _) = resolution_pair_from_bytes(&[*low, 0, *high]);
+            Action::ResolutionLock {
+                resolution: resolution_from_raw(capabilities, raw)?,
+            }
+        }
 
         [7, 0, 0] => Action::PollRateLoop,
 
@@ -763,7 +655,7 @@ fn key_event_from_raw(raw: &[u8]) -> crate::Result<KeyEvent> {
                 .iter()
                 .find(|(_, mapped_code)| *mapped_code == code)
             {
-                id.clone()
+                *id
             } else {
                 KeyMap::from_usb_code(HID_CONSUMER_PAGE, code)
                     .map_err(|_| {
@@ -801,9 +693,9 @@ fn key_event_from_raw(raw: &[u8]) -> crate::Result<KeyEvent> {
             // Multiple modifiers might work, but we'd need to figure out how to represent
             // that in RON/JSON serialized profiles.
             _ => {
-                return Err(crate::Error::InvalidProfile(format!(
-                    "Only one modifier is currently supported in key events."
-                )));
+                return Err(crate::Error::InvalidProfile(
+                    "Only one modifier is currently supported in key events.".to_string(),
+                ));
             }
         },
 
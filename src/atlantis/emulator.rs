@@ -0,0 +1,166 @@
+use super::hid::{frame, Command, MAX_PAYLOAD_LEN, REPORT_ID, REPORT_LEN};
+use super::{charge_state_to_raw, checksum, NUM_PROFILES};
+use crate::ChargeState;
+use crate::Transport;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Size of the emulated flash for each profile.
+const FLASH_SIZE: usize = 4096;
+
+const DEFAULT_BATTERY_MILLIVOLTS: u16 = 3900;
+
+/// Error codes returned by the emulator. The codes used by real firmware are
+/// unknown, so these only need to be non-zero.
+mod error_code {
+    pub const BAD_CHECKSUM: u8 = 1;
+    pub const UNKNOWN_COMMAND: u8 = 2;
+    pub const INVALID_ADDRESS: u8 = 3;
+    pub const INVALID_LENGTH: u8 = 4;
+    pub const INVALID_PROFILE: u8 = 5;
+}
+
+/// In-memory emulation of an Atlantis mouse.
+///
+/// Speaks the same report protocol as the real device and keeps a separate
+/// flash image for each profile, so it can be used as the transport for
+/// [`Atlantis`](super::Atlantis) without any hardware.
+pub struct Emulator {
+    state: RefCell<State>,
+}
+
+struct State {
+    flash: Vec<Vec<u8>>,
+    active_profile: usize,
+    battery_millivolts: u16,
+    charge_state: ChargeState,
+    responses: VecDeque<Vec<u8>>,
+}
+
+impl Emulator {
+    /// Creates an emulator with blank (zeroed) flash for every profile.
+    pub fn new() -> Self {
+        Self {
+            state: RefCell::new(State {
+                flash: vec![vec![0; FLASH_SIZE]; NUM_PROFILES],
+                active_profile: 0,
+                battery_millivolts: DEFAULT_BATTERY_MILLIVOLTS,
+                charge_state: ChargeState::Discharging,
+                responses: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Creates an emulator from existing flash images (e.g. dumped from a real
+    /// mouse). Missing profiles are blank and short images are zero padded.
+    pub fn with_flash(images: &[&[u8]]) -> crate::Result<Self> {
+        if images.len() > NUM_PROFILES || images.iter().any(|i| i.len() > FLASH_SIZE) {
+            return Err(crate::Error::InvalidProfile(format!(
+                "Emulator supports at most {NUM_PROFILES} flash images of {FLASH_SIZE} bytes"
+            )));
+        }
+        let emulator = Self::new();
+        for (flash, image) in emulator.state.borrow_mut().flash.iter_mut().zip(images) {
+            flash[..image.len()].copy_from_slice(image);
+        }
+        Ok(emulator)
+    }
+
+    /// Returns a copy of the flash image for a profile.
+    pub fn flash(&self, profile: usize) -> Vec<u8> {
+        self.state.borrow().flash[profile].clone()
+    }
+
+    /// Returns the index of the currently active profile.
+    pub fn active_profile(&self) -> usize {
+        self.state.borrow().active_profile
+    }
+
+    /// Sets the battery voltage reported to the host.
+    pub fn set_battery_voltage(&self, millivolts: u16) {
+        self.state.borrow_mut().battery_millivolts = millivolts;
+    }
+
+    /// Sets the battery charging state reported to the host. It's encoded with
+    /// the same guessed raw values that the driver decodes.
+    pub fn set_charge_state(&self, state: ChargeState) {
+        self.state.borrow_mut().charge_state = state;
+    }
+
+    fn handle(state: &mut State, request: &[u8]) -> Vec<u8> {
+        let cmd = request[1];
+        let address = u16::from_be_bytes([request[3], request[4]]);
+        let len = request[5] as usize;
+
+        if checksum(&request[..REPORT_LEN - 1]) != request[REPORT_LEN - 1] {
+            return frame(cmd, error_code::BAD_CHECKSUM, address, &[]);
+        }
+        if len > MAX_PAYLOAD_LEN {
+            return frame(cmd, error_code::INVALID_LENGTH, address, &[]);
+        }
+        let payload = &request[6..(6 + len)];
+        let Some(command) = Command::from_u8(cmd) else {
+            return frame(cmd, error_code::UNKNOWN_COMMAND, address, &[]);
+        };
+
+        match command {
+            Command::ReadFlash | Command::WriteFlash => {
+                let start = address as usize;
+                if start + len > FLASH_SIZE {
+                    return frame(cmd, error_code::INVALID_ADDRESS, address, payload);
+                }
+                let flash = &mut state.flash[state.active_profile][start..(start + len)];
+                if command == Command::WriteFlash {
+                    flash.copy_from_slice(payload);
+                }
+                frame(cmd, 0, address, flash)
+            }
+
+            Command::ReadActiveProfile => frame(cmd, 0, 0, &[state.active_profile as u8]),
+
+            Command::WriteActiveProfile => match payload {
+                [index] if (*index as usize) < NUM_PROFILES => {
+                    state.active_profile = *index as usize;
+                    frame(cmd, 0, 0, payload)
+                }
+                _ => frame(cmd, error_code::INVALID_PROFILE, 0, payload),
+            },
+
+            Command::ReadBatteryVoltage => {
+                let mv = state.battery_millivolts.to_be_bytes();
+                let charge_state = charge_state_to_raw(state.charge_state);
+                frame(cmd, 0, 0, &[0, charge_state, mv[0], mv[1]])
+            }
+        }
+    }
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for Emulator {
+    fn write(&self, report: &[u8]) -> crate::Result<usize> {
+        // Reports for other IDs or of the wrong size are silently dropped like a
+        // real device would.
+        if report.len() == REPORT_LEN && report[0] == REPORT_ID {
+            let mut state = self.state.borrow_mut();
+            let response = Self::handle(&mut state, report);
+            state.responses.push_back(response);
+        }
+        Ok(report.len())
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], _timeout: Option<Duration>) -> crate::Result<usize> {
+        // Never block: with no pending responses this behaves like a timeout.
+        let Some(response) = self.state.borrow_mut().responses.pop_front() else {
+            return Ok(0);
+        };
+        let len = response.len().min(buf.len());
+        buf[..len].copy_from_slice(&response[..len]);
+        Ok(len)
+    }
+}
@@ -0,0 +1,103 @@
+use super::{address, read_report, serialize_hex, Atlantis, Command};
+use crate::Transport;
+use serde::Serialize;
+
+/// Change reported by the mouse without being asked, e.g. after pressing the
+/// profile or resolution button.
+///
+/// Decoding is experimental. The format of these reports hasn't been
+/// documented, and no reports recorded from a real mouse have been checked yet,
+/// so they are decoded on a best effort basis by assuming they look like
+/// responses to the known commands. Anything else is passed on as
+/// [`Unknown`](Event::Unknown).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Active profile changed to the given index.
+    ActiveProfileChanged {
+        profile: usize,
+    },
+
+    /// Current resolution changed to the given index of the profile's
+    /// resolutions.
+    ResolutionIndexChanged {
+        index: usize,
+    },
+
+    BatteryVoltage {
+        millivolts: u16,
+    },
+
+    /// The device was disconnected. No further events will follow.
+    ConnectionLost,
+
+    /// A report that couldn't be decoded.
+    Unknown {
+        #[serde(serialize_with = "serialize_hex")]
+        report: Vec<u8>,
+    },
+}
+
+/// Blocking iterator over [`Event`]s from the mouse, returned by
+/// [`Atlantis::events`].
+pub struct Events<'a, T: Transport> {
+    atlantis: &'a Atlantis<T>,
+    connected: bool,
+}
+
+impl<T: Transport> Atlantis<T> {
+    /// Returns an iterator that waits for events from the mouse.
+    ///
+    /// The iterator ends after the device is disconnected, or when the
+    /// transport times out without a report, which only happens with transports
+    /// that never block (e.g. an [`Emulator`](super::Emulator)). Corrupt
+    /// reports are returned as errors, after which the iterator carries on.
+    pub fn events(&self) -> Events<'_, T> {
+        Events {
+            atlantis: self,
+            connected: true,
+        }
+    }
+}
+
+impl<T: Transport> Iterator for Events<'_, T> {
+    type Item = crate::Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.connected {
+            match read_report(&self.atlantis.device, None) {
+                Ok(Some(report)) => return Some(Ok(decode_event(report))),
+                // Not a vendor report.
+                Ok(None) => {}
+                Err(crate::Error::Timeout) => return None,
+                Err(crate::Error::Hid(_)) => {
+                    self.connected = false;
+                    return Some(Ok(Event::ConnectionLost));
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+fn decode_event(report: Vec<u8>) -> Event {
+    let address = u16::from_be_bytes([report[3], report[4]]) as usize;
+    let payload = &report[6..];
+    match Command::from_u8(report[1]) {
+        Some(Command::ReadActiveProfile | Command::WriteActiveProfile) => {
+            Event::ActiveProfileChanged {
+                profile: payload[0] as usize,
+            }
+        }
+        Some(Command::ReadFlash | Command::WriteFlash) if address == address::RESOLUTION_INDEX => {
+            Event::ResolutionIndexChanged {
+                index: payload[0] as usize,
+            }
+        }
+        Some(Command::ReadBatteryVoltage) => Event::BatteryVoltage {
+            millivolts: u16::from_be_bytes([payload[2], payload[3]]),
+        },
+        _ => Event::Unknown { report },
+    }
+}
@@ -1,17 +1,62 @@
 use super::checksum;
-use hidapi::HidDevice;
+use crate::Transport;
+use std::thread;
+use std::time::{Duration, Instant};
 
-const REPORT_ID: u8 = 8;
+mod pcap;
+pub use pcap::read_pcap;
+mod replay;
+pub use replay::Replay;
+mod trace;
+pub(super) use trace::serialize_hex;
+pub use trace::{
+    annotate_report, describe_report, CaptureRecord, Direction, ReportAnnotation, Trace,
+};
+
+pub(super) const REPORT_ID: u8 = 8;
+pub(super) const REPORT_LEN: usize = 17;
+pub(super) const MAX_PAYLOAD_LEN: usize = 10;
 
 pub struct Report {
-    cmd: Command,
-    error: u8,
-    address: u16,
-    payload: Vec<u8>,
+    pub(super) cmd: Command,
+    pub(super) error: u8,
+    pub(super) address: u16,
+    pub(super) payload: Vec<u8>,
+}
+
+impl Report {
+    /// Serializes the report into a full length, checksummed buffer.
+    pub(super) fn to_bytes(&self) -> Vec<u8> {
+        frame(self.cmd as u8, self.error, self.address, &self.payload)
+    }
 }
 
+/// Timeout and retry behaviour for requests to the mouse.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum Command {
+pub struct RequestPolicy {
+    /// How long to wait for a response after sending a request.
+    pub timeout: Duration,
+
+    /// How many times to re-send a request that got no matching response, or a
+    /// corrupt one.
+    pub retries: u32,
+
+    /// Delay before the first retry, doubled for each further retry.
+    pub backoff: Duration,
+}
+
+impl Default for RequestPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(1000),
+            retries: 2,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Command {
     ReadBatteryVoltage = 4,
 
     // Read / write flash for active profile.
@@ -23,7 +68,7 @@ enum Command {
 }
 
 impl Command {
-    fn from_u8(byte: u8) -> Option<Self> {
+    pub(super) fn from_u8(byte: u8) -> Option<Self> {
         match byte {
             4 => Some(Self::ReadBatteryVoltage),
             7 => Some(Self::WriteFlash),
@@ -33,39 +78,54 @@ impl Command {
             _ => None,
         }
     }
+
+    /// Whether requests with this command are for a flash address, which the
+    /// response echoes.
+    fn has_address(self) -> bool {
+        matches!(self, Self::ReadFlash | Self::WriteFlash)
+    }
 }
 
-pub fn read_battery_voltage(device: &HidDevice) -> crate::Result<u16> {
+/// Returns the whole battery report payload. Bytes 2-3 are the voltage.
+pub fn read_battery(device: &impl Transport, policy: &RequestPolicy) -> crate::Result<Vec<u8>> {
     let report = Report {
         cmd: Command::ReadBatteryVoltage,
         error: 0,
         address: 0,
         payload: Vec::new(),
     };
-    let response = make_request(device, &report, true)?;
-    Ok(u16::from_be_bytes([
-        response.payload[2],
-        response.payload[3],
-    ]))
+    make_request(device, policy, &report, true).map(|response| response.payload)
 }
 
-pub fn read_flash(device: &HidDevice, address: usize, length: usize) -> crate::Result<Vec<u8>> {
+pub fn read_flash(
+    device: &impl Transport,
+    policy: &RequestPolicy,
+    address: usize,
+    length: usize,
+) -> crate::Result<Vec<u8>> {
     let mut data = Vec::new();
     while data.len() < length {
         let report = Report {
             cmd: Command::ReadFlash,
             error: 0,
             address: (address + data.len()) as u16,
-            payload: vec![0; (length - data.len()).min(10)],
+            payload: vec![0; (length - data.len()).min(MAX_PAYLOAD_LEN)],
         };
-        data.append(&mut make_request(device, &report, false).map(|response| response.payload)?);
+        data.append(
+            &mut make_request(device, policy, &report, false).map(|response| response.payload)?,
+        );
     }
     Ok(data)
 }
 
-pub fn write_flash(device: &HidDevice, mut address: usize, mut data: Vec<u8>) -> crate::Result<()> {
-    while data.len() > 0 {
-        let payload: Vec<u8> = data.drain(..data.len().min(10)).collect();
+pub fn write_flash(
+    device: &impl Transport,
+    policy: &RequestPolicy,
+    mut address: usize,
+    mut data: Vec<u8>,
+) -> crate::Result<()> {
+    while !data.is_empty() {
+        let payload: Vec<u8> = data.drain(..data.len().min(MAX_PAYLOAD_LEN)).collect();
         let len = payload.len();
         let report = Report {
             cmd: Command::WriteFlash,
@@ -73,68 +133,173 @@ pub fn write_flash(device: &HidDevice, mut address: usize, mut data: Vec<u8>) ->
             address: address as u16,
             payload,
         };
-        make_request(device, &report, false).map(|_| ())?;
+        make_request(device, policy, &report, false).map(|_| ())?;
         address += len;
     }
     Ok(())
 }
 
-pub fn read_active_profile(device: &HidDevice) -> crate::Result<u8> {
+pub fn read_active_profile(device: &impl Transport, policy: &RequestPolicy) -> crate::Result<u8> {
     let report = Report {
         cmd: Command::ReadActiveProfile,
         error: 0,
         address: 0,
         payload: vec![],
     };
-    make_request(device, &report, true).map(|response| response.payload[0])
+    make_request(device, policy, &report, true).map(|response| response.payload[0])
 }
 
-pub fn write_active_profile(device: &HidDevice, profile_index: u8) -> crate::Result<()> {
+pub fn write_active_profile(
+    device: &impl Transport,
+    policy: &RequestPolicy,
+    profile_index: u8,
+) -> crate::Result<()> {
     let report = Report {
         cmd: Command::WriteActiveProfile,
         error: 0,
         address: 0,
         payload: vec![profile_index],
     };
-    make_request(device, &report, false).map(|_| ())
-}
-
-/// Writes a report to the device and attempts to read a matching response.
-fn make_request(device: &HidDevice, request: &Report, ignore_len: bool) -> crate::Result<Report> {
-    write_report(device, request)?;
-
-    // A request may result in multiple responses so skip the unwanted ones.
-    for _ in 0..3 {
-        match read_report(device, ignore_len) {
-            Ok(Some(response)) => {
-                if response.cmd == request.cmd
-                    && (ignore_len || response.payload.len() == request.payload.len())
-                {
-                    if response.error == 0 {
-                        return Ok(response);
-                    } else {
-                        return Err(crate::Error::MouseErrorResponse(response.error));
-                    }
+    make_request(device, policy, &report, false).map(|_| ())
+}
+
+/// Writes a report to the device and attempts to read a matching response,
+/// re-sending the request according to `policy` if none arrives in time.
+fn make_request(
+    device: &impl Transport,
+    policy: &RequestPolicy,
+    request: &Report,
+    ignore_len: bool,
+) -> crate::Result<Report> {
+    if request.payload.len() > MAX_PAYLOAD_LEN {
+        return Err(crate::Error::MalformedReport(request.payload.clone()));
+    }
+    let response = exchange(device, policy, &request.to_bytes(), |buf| {
+        Ok(parse_report(buf, ignore_len)?.filter(|response| {
+            response.cmd == request.cmd
+                && (ignore_len || response.payload.len() == request.payload.len())
+                // A late response to an earlier attempt, or to the previous
+                // chunk, must not be taken for this one.
+                && (!request.cmd.has_address() || response.address == request.address)
+        }))
+    })?;
+    if response.error == 0 {
+        Ok(response)
+    } else {
+        Err(crate::Error::MouseErrorResponse(response.error))
+    }
+}
+
+/// Sends a request with a raw command byte, which doesn't need to be a known
+/// [`Command`], and returns the raw response.
+pub(super) fn raw_request(
+    device: &impl Transport,
+    policy: &RequestPolicy,
+    cmd: u8,
+    address: u16,
+    payload: &[u8],
+) -> crate::Result<Vec<u8>> {
+    exchange(device, policy, &frame(cmd, 0, address, payload), |buf| {
+        Ok((buf[1] == cmd).then_some(buf))
+    })
+}
+
+/// Writes a raw request to the device and passes each report read back to
+/// `accept` until it returns a response, re-sending the request according to
+/// `policy` if none arrives in time or a corrupt report is read.
+///
+/// If every attempt fails, the error is the one from the last attempt.
+fn exchange<R>(
+    device: &impl Transport,
+    policy: &RequestPolicy,
+    request: &[u8],
+    mut accept: impl FnMut(Vec<u8>) -> crate::Result<Option<R>>,
+) -> crate::Result<R> {
+    // Whether the device sent anything at all, to tell a sleeping mouse apart
+    // from one that just didn't answer this request.
+    let mut awake = false;
+    let mut corrupt = None;
+
+    for attempt in 0..=policy.retries {
+        if attempt > 0 {
+            thread::sleep(policy.backoff * 2u32.saturating_pow(attempt - 1));
+        }
+        write_report(device, request)?;
+        corrupt = None;
+
+        // A request may result in multiple responses so skip the unwanted ones.
+        let deadline = Instant::now() + policy.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            let response = read_report(device, Some(remaining))
+                .and_then(|buf| buf.map_or(Ok(None), &mut accept));
+            match response {
+                Ok(Some(response)) => return Ok(response),
+                Ok(None) => awake = true,
+                Err(crate::Error::Timeout) => break,
+                // Most likely the response was garbled on the way, so ask again.
+                Err(
+                    e @ (crate::Error::BadChecksum(_)
+                    | crate::Error::MalformedReport(_)
+                    | crate::Error::ShortRead { .. }),
+                ) => {
+                    awake = true;
+                    corrupt = Some(e);
+                    break;
                 }
+                Err(e) => return Err(e),
             }
-            Ok(None) => {}
-            Err(e) => return Err(e),
         }
     }
 
-    Err(crate::Error::NoResponse)
+    Err(corrupt.unwrap_or(if awake {
+        crate::Error::Timeout
+    } else {
+        crate::Error::DeviceAsleep
+    }))
 }
 
-fn read_report(device: &HidDevice, ignore_len: bool) -> crate::Result<Option<Report>> {
-    let mut buf = vec![0; 17];
-    let len = device.read(&mut buf)?;
+/// Reads a single raw report, returning `None` for reports that aren't
+/// protocol reports and [`Timeout`](crate::Error::Timeout) if nothing
+/// arrives.
+pub(super) fn read_report(
+    device: &impl Transport,
+    timeout: Option<Duration>,
+) -> crate::Result<Option<Vec<u8>>> {
+    let mut buf = vec![0; REPORT_LEN];
+    let len = device.read_timeout(&mut buf, timeout)?;
+    if len == 0 {
+        return Err(crate::Error::Timeout);
+    }
     if buf[0] != REPORT_ID {
         // Wrong report.
         return Ok(None);
     }
-    assert!(len == 17);
-    assert!(checksum(&buf[..16]) == buf[16]);
-    let payload_len = if ignore_len { 10 } else { buf[5] as usize };
+    if len != REPORT_LEN {
+        return Err(crate::Error::ShortRead {
+            expected: REPORT_LEN,
+            actual: len,
+        });
+    }
+    if checksum(&buf[..16]) != buf[16] {
+        return Err(crate::Error::BadChecksum(buf));
+    }
+    Ok(Some(buf))
+}
+
+/// Parses a raw report, returning `None` for responses to unknown commands.
+fn parse_report(buf: Vec<u8>, ignore_len: bool) -> crate::Result<Option<Report>> {
+    let payload_len = if ignore_len {
+        MAX_PAYLOAD_LEN
+    } else {
+        buf[5] as usize
+    };
+    if payload_len > MAX_PAYLOAD_LEN {
+        return Err(crate::Error::MalformedReport(buf));
+    }
     let Some(cmd) = Command::from_u8(buf[1]) else {
         // Response for unknown command.
         return Ok(None);
@@ -147,15 +312,163 @@ fn read_report(device: &HidDevice, ignore_len: bool) -> crate::Result<Option<Rep
     }))
 }
 
-fn write_report(device: &HidDevice, report: &Report) -> crate::Result<()> {
-    assert!(report.payload.len() <= 10);
-    let mut buf = vec![REPORT_ID, report.cmd as u8, report.error];
-    buf.extend(u16::to_be_bytes(report.address));
-    buf.push(report.payload.len() as u8);
-    buf.extend(&report.payload);
-    buf.resize(16, 0);
-    buf.push(checksum(&buf));
-    assert!(device.write(&buf)? == 17);
+fn write_report(device: &impl Transport, report: &[u8]) -> crate::Result<()> {
+    let len = device.write(report)?;
+    if len != REPORT_LEN {
+        return Err(crate::Error::ShortWrite {
+            expected: REPORT_LEN,
+            actual: len,
+        });
+    }
 
     Ok(())
 }
+
+/// Builds a full report buffer from its fields. `cmd` is a raw byte so that
+/// responses to unknown commands can also be framed.
+pub(super) fn frame(cmd: u8, error: u8, address: u16, payload: &[u8]) -> Vec<u8> {
+    let mut buf = vec![REPORT_ID, cmd, error];
+    buf.extend(u16::to_be_bytes(address));
+    buf.push(payload.len() as u8);
+    buf.extend(payload);
+    buf.resize(REPORT_LEN - 1, 0);
+    buf.push(checksum(&buf));
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+
+    const POLICY: RequestPolicy = RequestPolicy {
+        timeout: Duration::from_millis(100),
+        retries: 1,
+        backoff: Duration::ZERO,
+    };
+
+    /// Transport that answers reads with scripted reports, or times out once
+    /// they run out. An empty report stands for a single timeout.
+    #[derive(Default)]
+    struct Script {
+        responses: RefCell<VecDeque<Vec<u8>>>,
+        writes: Cell<usize>,
+    }
+
+    impl Script {
+        fn new(responses: impl IntoIterator<Item = Vec<u8>>) -> Self {
+            Self {
+                responses: RefCell::new(responses.into_iter().collect()),
+                writes: Cell::new(0),
+            }
+        }
+    }
+
+    impl Transport for Script {
+        fn write(&self, report: &[u8]) -> crate::Result<usize> {
+            self.writes.set(self.writes.get() + 1);
+            Ok(report.len())
+        }
+
+        fn read_timeout(&self, buf: &mut [u8], _timeout: Option<Duration>) -> crate::Result<usize> {
+            let Some(report) = self.responses.borrow_mut().pop_front() else {
+                return Ok(0);
+            };
+            buf[..report.len()].copy_from_slice(&report);
+            Ok(report.len())
+        }
+    }
+
+    fn active_profile_response(profile: u8) -> Vec<u8> {
+        frame(Command::ReadActiveProfile as u8, 0, 0, &[profile])
+    }
+
+    #[test]
+    fn exchange_skips_unrelated_reports() {
+        let mut mouse_report = vec![1, 0, 5, 0];
+        mouse_report.resize(REPORT_LEN, 0);
+        let device = Script::new([
+            mouse_report,
+            frame(Command::ReadBatteryVoltage as u8, 0, 0, &[0, 0, 0x0F, 0xA0]),
+            frame(0x20, 0, 0, &[]),
+            active_profile_response(2),
+        ]);
+        assert_eq!(read_active_profile(&device, &POLICY).unwrap(), 2);
+        assert_eq!(device.writes.get(), 1);
+    }
+
+    #[test]
+    fn exchange_reports_silent_device_as_asleep() {
+        let device = Script::default();
+        let result = read_active_profile(&device, &POLICY);
+        assert!(
+            matches!(result, Err(crate::Error::DeviceAsleep)),
+            "{result:?}"
+        );
+        assert_eq!(device.writes.get(), 2);
+    }
+
+    #[test]
+    fn exchange_reports_unanswered_request_as_timeout() {
+        let device = Script::new([frame(Command::ReadBatteryVoltage as u8, 0, 0, &[])]);
+        let result = read_active_profile(&device, &POLICY);
+        assert!(matches!(result, Err(crate::Error::Timeout)), "{result:?}");
+        assert_eq!(device.writes.get(), 2);
+    }
+
+    #[test]
+    fn exchange_retries_bad_checksum() {
+        let mut corrupt = active_profile_response(3);
+        corrupt[REPORT_LEN - 1] ^= 0xFF;
+        let device = Script::new([corrupt, active_profile_response(3)]);
+        assert_eq!(read_active_profile(&device, &POLICY).unwrap(), 3);
+        assert_eq!(device.writes.get(), 2);
+    }
+
+    #[test]
+    fn exchange_returns_bad_checksum_after_last_retry() {
+        let mut corrupt = active_profile_response(3);
+        corrupt[REPORT_LEN - 1] ^= 0xFF;
+        let device = Script::new([corrupt.clone(), corrupt.clone()]);
+        let result = read_active_profile(&device, &POLICY);
+        assert!(
+            matches!(&result, Err(crate::Error::BadChecksum(report)) if *report == corrupt),
+            "{result:?}"
+        );
+        assert_eq!(device.writes.get(), 2);
+    }
+
+    #[test]
+    fn exchange_skips_late_flash_response() {
+        let flash: Vec<u8> = (0..20).collect();
+        let read_response = |address: usize| {
+            frame(
+                Command::ReadFlash as u8,
+                0,
+                address as u16,
+                &flash[address..(address + MAX_PAYLOAD_LEN)],
+            )
+        };
+        // The first request times out and is answered late, after the retry.
+        let device = Script::new([
+            Vec::new(),
+            read_response(0),
+            read_response(0),
+            read_response(10),
+        ]);
+        assert_eq!(read_flash(&device, &POLICY, 0, 20).unwrap(), flash);
+        assert_eq!(device.writes.get(), 3);
+    }
+
+    #[test]
+    fn exchange_returns_mouse_error_without_retrying() {
+        let device = Script::new([frame(Command::ReadActiveProfile as u8, 1, 0, &[0])]);
+        let result = read_active_profile(&device, &POLICY);
+        assert!(
+            matches!(result, Err(crate::Error::MouseErrorResponse(1))),
+            "{result:?}"
+        );
+        assert_eq!(device.writes.get(), 1);
+    }
+}
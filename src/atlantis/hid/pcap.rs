@@ -0,0 +1,591 @@
+use super::{CaptureRecord, Direction, REPORT_ID};
+
+const LINKTYPE_USB_LINUX: u32 = 189;
+const LINKTYPE_USB_LINUX_MMAPPED: u32 = 220;
+const LINKTYPE_USBPCAP: u32 = 249;
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0A0D0D0A;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+const PCAPNG_OPTION_TSRESOL: u16 = 9;
+
+/// Extracts the vendor reports exchanged with a mouse from a USB packet
+/// capture, i.e. a pcap or pcapng file recorded with Linux usbmon or Windows
+/// USBPcap.
+///
+/// Reports sent by the host and received from the device are returned in
+/// capture order, with times relative to the first packet. Only transfers
+/// starting with the vendor report ID are included, so the capture should
+/// only contain traffic for the mouse.
+pub fn read_pcap(data: &[u8]) -> crate::Result<Vec<CaptureRecord>> {
+    let packets = match data.get(..4) {
+        Some([0x0A, 0x0D, 0x0D, 0x0A]) => read_pcapng_packets(data)?,
+        Some(_) => read_pcap_packets(data)?,
+        None => return Err(truncated()),
+    };
+
+    let start = packets.first().map_or(0, |p| p.time_us);
+    let mut records = Vec::new();
+    for packet in packets {
+        let Some((direction, report)) = usb_transfer(packet.linktype, packet.data)? else {
+            continue;
+        };
+        if report.first() == Some(&REPORT_ID) {
+            records.push(CaptureRecord {
+                time_us: packet.time_us.saturating_sub(start),
+                direction,
+                report: report.to_vec(),
+            });
+        }
+    }
+    Ok(records)
+}
+
+struct Packet<'a> {
+    linktype: u32,
+    time_us: u64,
+    data: &'a [u8],
+}
+
+/// Reads integers from a capture in either byte order.
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, offset: usize, len: usize) -> crate::Result<&'a [u8]> {
+        self.data.get(offset..(offset + len)).ok_or_else(truncated)
+    }
+
+    fn u16(&self, offset: usize) -> crate::Result<u16> {
+        let bytes = self.bytes(offset, 2)?.try_into().unwrap();
+        Ok(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: usize) -> crate::Result<u32> {
+        let bytes = self.bytes(offset, 4)?.try_into().unwrap();
+        Ok(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+}
+
+fn read_pcap_packets(data: &[u8]) -> crate::Result<Vec<Packet<'_>>> {
+    let (little_endian, nanoseconds) = match data[..4] {
+        [0xD4, 0xC3, 0xB2, 0xA1] => (true, false),
+        [0x4D, 0x3C, 0xB2, 0xA1] => (true, true),
+        [0xA1, 0xB2, 0xC3, 0xD4] => (false, false),
+        [0xA1, 0xB2, 0x3C, 0x4D] => (false, true),
+        _ => {
+            return Err(crate::Error::MalformedCapture(
+                "Not a pcap or pcapng file".to_string(),
+            ))
+        }
+    };
+    let reader = Reader {
+        data,
+        little_endian,
+    };
+    // The upper bits of the link type field hold unrelated flags.
+    let linktype = reader.u32(20)? & 0xFFFF;
+
+    let mut packets = Vec::new();
+    let mut offset = 24;
+    while offset < data.len() {
+        let seconds = reader.u32(offset)? as u64;
+        let fraction = reader.u32(offset + 4)? as u64;
+        let len = reader.u32(offset + 8)? as usize;
+        packets.push(Packet {
+            linktype,
+            time_us: seconds * 1_000_000
+                + if nanoseconds {
+                    fraction / 1000
+                } else {
+                    fraction
+                },
+            data: reader.bytes(offset + 16, len)?,
+        });
+        offset += 16 + len;
+    }
+    Ok(packets)
+}
+
+fn read_pcapng_packets(data: &[u8]) -> crate::Result<Vec<Packet<'_>>> {
+    let mut reader = Reader {
+        data,
+        little_endian: true,
+    };
+    // Link type and timestamp units per second of each interface.
+    let mut interfaces: Vec<(u32, u64)> = Vec::new();
+    let mut last_time_us = 0;
+
+    let mut packets = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        if reader.u32(offset)? == PCAPNG_SECTION_HEADER {
+            // Each section can have a different byte order.
+            reader.little_endian = true;
+            reader.little_endian = match reader.u32(offset + 8)? {
+                PCAPNG_BYTE_ORDER_MAGIC => true,
+                magic if magic == PCAPNG_BYTE_ORDER_MAGIC.swap_bytes() => false,
+                _ => {
+                    return Err(crate::Error::MalformedCapture(
+                        "Bad pcapng byte order magic".to_string(),
+                    ))
+                }
+            };
+            interfaces.clear();
+        }
+
+        let block_type = reader.u32(offset)?;
+        let block_len = reader.u32(offset + 4)? as usize;
+        if block_len < 12 || !block_len.is_multiple_of(4) {
+            return Err(crate::Error::MalformedCapture(format!(
+                "Bad pcapng block length {block_len} at offset {offset}"
+            )));
+        }
+        // The block ends with its length again, which must also be there.
+        reader.bytes(offset, block_len)?;
+        let body = Reader {
+            data: reader.bytes(offset + 8, block_len - 12)?,
+            ..reader
+        };
+
+        match block_type {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                let linktype = body.u16(0)? as u32;
+                let mut units_per_second = 1_000_000;
+                let mut option = 8;
+                while option + 4 <= body.data.len() {
+                    let code = body.u16(option)?;
+                    let len = body.u16(option + 2)? as usize;
+                    if code == 0 {
+                        break;
+                    }
+                    if code == PCAPNG_OPTION_TSRESOL {
+                        let resolution = body.bytes(option + 4, 1)?[0];
+                        let exponent = (resolution & 0x7F) as u32;
+                        units_per_second = if resolution & 0x80 == 0 {
+                            10u64.saturating_pow(exponent)
+                        } else {
+                            2u64.saturating_pow(exponent)
+                        };
+                    }
+                    option += 4 + len.next_multiple_of(4);
+                }
+                interfaces.push((linktype, units_per_second));
+            }
+
+            PCAPNG_ENHANCED_PACKET => {
+                let interface = body.u32(0)? as usize;
+                let &(linktype, units_per_second) = interfaces.get(interface).ok_or_else(|| {
+                    crate::Error::MalformedCapture(format!("Unknown pcapng interface {interface}"))
+                })?;
+                let time = ((body.u32(4)? as u64) << 32) | body.u32(8)? as u64;
+                last_time_us = (time as u128 * 1_000_000 / units_per_second.max(1) as u128) as u64;
+                let len = body.u32(12)? as usize;
+                packets.push(Packet {
+                    linktype,
+                    time_us: last_time_us,
+                    data: body.bytes(20, len)?,
+                });
+            }
+
+            PCAPNG_SIMPLE_PACKET => {
+                let &(linktype, _) = interfaces.first().ok_or_else(|| {
+                    crate::Error::MalformedCapture("Packet before any pcapng interface".to_string())
+                })?;
+                let len = (body.u32(0)? as usize).min(body.data.len().saturating_sub(4));
+                packets.push(Packet {
+                    linktype,
+                    // Simple packets have no timestamp.
+                    time_us: last_time_us,
+                    data: body.bytes(4, len)?,
+                });
+            }
+
+            _ => {}
+        }
+        offset += block_len;
+    }
+    Ok(packets)
+}
+
+/// Returns the direction and data of a USB transfer, or `None` if the packet
+/// carries no data (e.g. an IN request or an OUT completion).
+fn usb_transfer(linktype: u32, packet: &[u8]) -> crate::Result<Option<(Direction, &[u8])>> {
+    let reader = Reader {
+        data: packet,
+        little_endian: true,
+    };
+    let (direction, data) = match linktype {
+        LINKTYPE_USB_LINUX | LINKTYPE_USB_LINUX_MMAPPED => {
+            // Submissions carry data sent by the host and completions carry
+            // data received from the device.
+            let direction = match reader.bytes(8, 1)?[0] {
+                b'S' => Direction::Out,
+                b'C' => Direction::In,
+                _ => return Ok(None),
+            };
+            let header_len = if linktype == LINKTYPE_USB_LINUX_MMAPPED {
+                // Isochronous descriptors follow the header.
+                64 + reader.u32(60)? as usize * 16
+            } else {
+                48
+            };
+            let len = reader.u32(36)? as usize;
+            (direction, reader.bytes(header_len, len)?)
+        }
+
+        LINKTYPE_USBPCAP => {
+            let header_len = reader.u16(0)? as usize;
+            // Bit 0 of the info field is set for packets from the device.
+            let direction = if reader.bytes(16, 1)?[0] & 1 == 0 {
+                Direction::Out
+            } else {
+                Direction::In
+            };
+            let mut data = reader.bytes(header_len, packet.len().saturating_sub(header_len))?;
+            // The setup stage of a control transfer starts with the setup packet.
+            let control_setup_stage = reader.bytes(22, 1)?[0] == 2 && reader.bytes(27, 1)?[0] == 0;
+            if control_setup_stage {
+                data = data.get(8..).unwrap_or_default();
+            }
+            (direction, data)
+        }
+
+        _ => {
+            return Err(crate::Error::MalformedCapture(format!(
+                "Unsupported link type {linktype}, expected a usbmon or USBPcap capture"
+            )))
+        }
+    };
+
+    Ok((!data.is_empty()).then_some((direction, data)))
+}
+
+fn truncated() -> crate::Error {
+    crate::Error::MalformedCapture("Capture is truncated".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds capture data in either byte order.
+    struct Writer {
+        little_endian: bool,
+        data: Vec<u8>,
+    }
+
+    impl Writer {
+        fn new(little_endian: bool) -> Self {
+            Self {
+                little_endian,
+                data: Vec::new(),
+            }
+        }
+
+        fn u16(&mut self, val: u16) -> &mut Self {
+            let bytes = if self.little_endian {
+                val.to_le_bytes()
+            } else {
+                val.to_be_bytes()
+            };
+            self.bytes(&bytes)
+        }
+
+        fn u32(&mut self, val: u32) -> &mut Self {
+            let bytes = if self.little_endian {
+                val.to_le_bytes()
+            } else {
+                val.to_be_bytes()
+            };
+            self.bytes(&bytes)
+        }
+
+        fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+            self.data.extend(bytes);
+            self
+        }
+
+        /// Appends a pcapng block, padding its body to 4 bytes.
+        fn block(&mut self, block_type: u32, body: &[u8]) -> &mut Self {
+            let padded_len = body.len().next_multiple_of(4);
+            let block_len = (12 + padded_len) as u32;
+            self.u32(block_type).u32(block_len).bytes(body);
+            self.bytes(&vec![0; padded_len - body.len()]);
+            self.u32(block_len)
+        }
+
+        fn body(&self) -> Self {
+            Self::new(self.little_endian)
+        }
+    }
+
+    fn report(cmd: u8) -> Vec<u8> {
+        super::super::frame(cmd, 0, 0, &[])
+    }
+
+    /// usbmon packet with the 48 or 64 byte header, which is little endian
+    /// whatever the capture's byte order.
+    fn usbmon(event: u8, data: &[u8], mmapped: bool) -> Vec<u8> {
+        let mut packet = vec![0; if mmapped { 64 } else { 48 }];
+        packet[8] = event;
+        packet[36..40].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        packet.extend(data);
+        packet
+    }
+
+    /// USBPcap packet, with the control stage byte for control transfers.
+    fn usbpcap(from_device: bool, control_stage: Option<u8>, data: &[u8]) -> Vec<u8> {
+        let header_len = if control_stage.is_some() { 28 } else { 27 };
+        let mut packet = vec![0; header_len];
+        packet[..2].copy_from_slice(&(header_len as u16).to_le_bytes());
+        packet[16] = from_device as u8;
+        // Transfer type: control or interrupt.
+        packet[22] = if control_stage.is_some() { 2 } else { 1 };
+        if let Some(stage) = control_stage {
+            packet[27] = stage;
+        }
+        packet.extend(data);
+        packet
+    }
+
+    fn pcap(
+        little_endian: bool,
+        nanoseconds: bool,
+        linktype: u32,
+        packets: &[(u32, u32, Vec<u8>)],
+    ) -> Vec<u8> {
+        let mut writer = Writer::new(little_endian);
+        writer
+            .u32(if nanoseconds { 0xA1B23C4D } else { 0xA1B2C3D4 })
+            .u16(2)
+            .u16(4)
+            .u32(0)
+            .u32(0)
+            .u32(65535)
+            .u32(linktype);
+        for (seconds, fraction, data) in packets {
+            writer
+                .u32(*seconds)
+                .u32(*fraction)
+                .u32(data.len() as u32)
+                .u32(data.len() as u32)
+                .bytes(data);
+        }
+        writer.data
+    }
+
+    fn section_header(writer: &mut Writer) {
+        let mut body = writer.body();
+        body.u32(PCAPNG_BYTE_ORDER_MAGIC)
+            .u16(1)
+            .u16(0)
+            .bytes(&[0xFF; 8]);
+        writer.block(PCAPNG_SECTION_HEADER, &body.data);
+    }
+
+    fn interface(writer: &mut Writer, linktype: u16, tsresol: Option<u8>) {
+        let mut body = writer.body();
+        body.u16(linktype).u16(0).u32(65535);
+        if let Some(tsresol) = tsresol {
+            body.u16(PCAPNG_OPTION_TSRESOL)
+                .u16(1)
+                .bytes(&[tsresol, 0, 0, 0]);
+        }
+        body.u16(0).u16(0);
+        writer.block(PCAPNG_INTERFACE_DESCRIPTION, &body.data);
+    }
+
+    fn enhanced_packet(writer: &mut Writer, interface: u32, time: u64, data: &[u8]) {
+        let mut body = writer.body();
+        body.u32(interface)
+            .u32((time >> 32) as u32)
+            .u32(time as u32)
+            .u32(data.len() as u32)
+            .u32(data.len() as u32)
+            .bytes(data);
+        writer.block(PCAPNG_ENHANCED_PACKET, &body.data);
+    }
+
+    fn record(time_us: u64, direction: Direction, report: Vec<u8>) -> CaptureRecord {
+        CaptureRecord {
+            time_us,
+            direction,
+            report,
+        }
+    }
+
+    fn assert_malformed(data: &[u8]) {
+        let result = read_pcap(data);
+        assert!(
+            matches!(result, Err(crate::Error::MalformedCapture(_))),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn pcap_usbmon() {
+        let data = pcap(
+            true,
+            false,
+            LINKTYPE_USB_LINUX,
+            &[
+                (10, 500, usbmon(b'S', &report(8), false)),
+                // IN request without data, and another device's report.
+                (10, 600, usbmon(b'S', &[], false)),
+                (10, 700, usbmon(b'C', &[1, 0, 0, 0], false)),
+                (11, 0, usbmon(b'C', &report(8), false)),
+                (11, 100, usbmon(b'E', &report(8), false)),
+            ],
+        );
+        assert_eq!(
+            read_pcap(&data).unwrap(),
+            [
+                record(0, Direction::Out, report(8)),
+                record(999_500, Direction::In, report(8)),
+            ]
+        );
+    }
+
+    #[test]
+    fn big_endian_nanosecond_pcap_usbmon_mmapped() {
+        let data = pcap(
+            false,
+            true,
+            LINKTYPE_USB_LINUX_MMAPPED,
+            &[
+                (1, 0, usbmon(b'S', &report(14), true)),
+                (1, 2_500_000, usbmon(b'C', &report(14), true)),
+            ],
+        );
+        assert_eq!(
+            read_pcap(&data).unwrap(),
+            [
+                record(0, Direction::Out, report(14)),
+                record(2500, Direction::In, report(14)),
+            ]
+        );
+    }
+
+    #[test]
+    fn pcapng_usbpcap() {
+        let mut setup = vec![0x21, 0x09, 0x08, 0x02, 0x01, 0x00, 0x11, 0x00];
+        setup.extend(report(7));
+
+        let mut writer = Writer::new(true);
+        section_header(&mut writer);
+        // Nanosecond timestamps.
+        interface(&mut writer, LINKTYPE_USBPCAP as u16, Some(9));
+        enhanced_packet(
+            &mut writer,
+            0,
+            5_000_000_000,
+            &usbpcap(false, Some(0), &setup),
+        );
+        // Status stage of the same control transfer, without data.
+        enhanced_packet(&mut writer, 0, 5_000_100_000, &usbpcap(true, Some(2), &[]));
+        enhanced_packet(
+            &mut writer,
+            0,
+            5_001_000_000,
+            &usbpcap(true, None, &report(7)),
+        );
+        assert_eq!(
+            read_pcap(&writer.data).unwrap(),
+            [
+                record(0, Direction::Out, report(7)),
+                record(1000, Direction::In, report(7)),
+            ]
+        );
+    }
+
+    #[test]
+    fn big_endian_pcapng_simple_packets() {
+        let mut writer = Writer::new(false);
+        section_header(&mut writer);
+        interface(&mut writer, LINKTYPE_USB_LINUX as u16, None);
+        enhanced_packet(&mut writer, 0, 2_000_000, &usbmon(b'S', &report(4), false));
+        let mut body = writer.body();
+        let packet = usbmon(b'C', &report(4), false);
+        body.u32(packet.len() as u32).bytes(&packet);
+        writer.block(PCAPNG_SIMPLE_PACKET, &body.data);
+        assert_eq!(
+            read_pcap(&writer.data).unwrap(),
+            [
+                record(0, Direction::Out, report(4)),
+                // Simple packets get the time of the packet before them.
+                record(0, Direction::In, report(4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_captures_are_rejected() {
+        assert_malformed(&[]);
+        assert_malformed(&[0xD4, 0xC3]);
+        assert_malformed(&[0, 1, 2, 3, 4, 5, 6, 7]);
+
+        let data = pcap(
+            true,
+            false,
+            LINKTYPE_USB_LINUX,
+            &[(0, 0, usbmon(b'S', &report(8), false))],
+        );
+        // Pcap file header, packet header and packet data.
+        assert_malformed(&data[..20]);
+        assert_malformed(&data[..30]);
+        assert_malformed(&data[..(data.len() - 1)]);
+        // usbmon header.
+        let mut packet = vec![0; 20];
+        packet[8] = b'S';
+        let short = pcap(true, false, LINKTYPE_USB_LINUX, &[(0, 0, packet)]);
+        assert_malformed(&short);
+
+        let mut writer = Writer::new(true);
+        section_header(&mut writer);
+        interface(&mut writer, LINKTYPE_USB_LINUX as u16, None);
+        enhanced_packet(&mut writer, 0, 0, &usbmon(b'S', &report(8), false));
+        assert!(read_pcap(&writer.data).is_ok());
+        assert_malformed(&writer.data[..(writer.data.len() - 4)]);
+        assert_malformed(&writer.data[..30]);
+    }
+
+    #[test]
+    fn malformed_pcapng_blocks_are_rejected() {
+        // Block length that isn't a multiple of 4.
+        let mut writer = Writer::new(true);
+        section_header(&mut writer);
+        writer.u32(PCAPNG_INTERFACE_DESCRIPTION).u32(13);
+        assert_malformed(&writer.data);
+
+        // Packet for an interface that wasn't described.
+        let mut writer = Writer::new(true);
+        section_header(&mut writer);
+        enhanced_packet(&mut writer, 0, 0, &usbmon(b'S', &report(8), false));
+        assert_malformed(&writer.data);
+
+        // Bad byte order magic.
+        let mut writer = Writer::new(true);
+        writer.block(PCAPNG_SECTION_HEADER, &[0; 16]);
+        assert_malformed(&writer.data);
+    }
+
+    #[test]
+    fn unsupported_link_type_is_rejected() {
+        let data = pcap(true, false, 1, &[(0, 0, vec![0; 64])]);
+        assert_malformed(&data);
+    }
+}
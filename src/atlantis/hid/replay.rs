@@ -0,0 +1,91 @@
+use super::{describe_report, CaptureRecord, Direction};
+use crate::Transport;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::time::Duration;
+
+/// Transport that replays a capture made with [`Trace`](super::Trace).
+///
+/// Recorded responses are served in order, and any request that differs from
+/// the recording fails with [`ReplayMismatch`](crate::Error::ReplayMismatch).
+/// This makes a capture of a real exchange usable as a deterministic device.
+pub struct Replay {
+    records: RefCell<VecDeque<CaptureRecord>>,
+}
+
+impl Replay {
+    pub fn new(records: impl IntoIterator<Item = CaptureRecord>) -> Self {
+        Self {
+            records: RefCell::new(records.into_iter().collect()),
+        }
+    }
+
+    /// Reads a capture file of JSON lines, ignoring blank lines.
+    pub fn from_reader(reader: impl BufRead) -> crate::Result<Self> {
+        let mut records = Vec::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            records.push(
+                serde_json::from_str(&line).map_err(|e| {
+                    crate::Error::MalformedCapture(format!("Line {}: {}", i + 1, e))
+                })?,
+            );
+        }
+        Ok(Self::new(records))
+    }
+
+    /// Number of recorded reports that haven't been replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.records.borrow().len()
+    }
+
+    /// Checks that the whole recording has been replayed.
+    pub fn finish(&self) -> crate::Result<()> {
+        match self.records.borrow().front() {
+            Some(record) => Err(mismatch(Some(record), "end of exchange".to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Transport for Replay {
+    fn write(&self, report: &[u8]) -> crate::Result<usize> {
+        let mut records = self.records.borrow_mut();
+        match records.front() {
+            Some(record) if record.direction == Direction::Out && record.report == report => {
+                records.pop_front();
+                Ok(report.len())
+            }
+            record => Err(mismatch(record, format!("-> {}", describe_report(report)))),
+        }
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], _timeout: Option<Duration>) -> crate::Result<usize> {
+        let mut records = self.records.borrow_mut();
+        match records.front() {
+            Some(record) if record.direction == Direction::In => {
+                let len = record.report.len().min(buf.len());
+                buf[..len].copy_from_slice(&record.report[..len]);
+                records.pop_front();
+                Ok(len)
+            }
+            record => Err(mismatch(record, "<- read".to_string())),
+        }
+    }
+}
+
+fn mismatch(expected: Option<&CaptureRecord>, actual: String) -> crate::Error {
+    let expected = match expected {
+        Some(record) if record.direction == Direction::Out => {
+            format!("-> {}", describe_report(&record.report))
+        }
+        Some(record) if record.report.is_empty() => "<- (timeout)".to_string(),
+        Some(record) => format!("<- {}", describe_report(&record.report)),
+        None => "end of capture".to_string(),
+    };
+    crate::Error::ReplayMismatch { expected, actual }
+}
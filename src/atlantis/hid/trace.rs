@@ -0,0 +1,216 @@
+use super::{Command, MAX_PAYLOAD_LEN, REPORT_ID, REPORT_LEN};
+use crate::atlantis::checksum;
+use crate::atlantis::image::flash_fields;
+use crate::{Transport, UsbInfo};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// Direction of a traced report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Sent from the host to the mouse.
+    Out,
+
+    /// Received by the host from the mouse.
+    In,
+}
+
+/// A single report exchanged with the mouse, as stored in a capture file.
+///
+/// Capture files contain one JSON encoded record per line. An incoming record
+/// with an empty report means that the read timed out.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptureRecord {
+    /// Microseconds since the trace started.
+    pub time_us: u64,
+
+    pub direction: Direction,
+
+    /// Raw report including the report ID, stored as space separated hex.
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub report: Vec<u8>,
+}
+
+/// Transport wrapper that logs every report exchanged with the mouse.
+///
+/// Reports can be logged as human readable lines with decoded fields, and/or
+/// saved as [`CaptureRecord`]s for later analysis.
+pub struct Trace<T: Transport> {
+    inner: T,
+    start: Instant,
+    log: Option<RefCell<Box<dyn Write>>>,
+    capture: Option<RefCell<Box<dyn Write>>>,
+}
+
+impl<T: Transport> Trace<T> {
+    /// Wraps a transport without logging anything yet.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            start: Instant::now(),
+            log: None,
+            capture: None,
+        }
+    }
+
+    /// Logs each report with its decoded fields to `writer`.
+    pub fn log_to(mut self, writer: impl Write + 'static) -> Self {
+        self.log = Some(RefCell::new(Box::new(writer)));
+        self
+    }
+
+    /// Saves each report as a JSON line [`CaptureRecord`] to `writer`.
+    pub fn capture_to(mut self, writer: impl Write + 'static) -> Self {
+        self.capture = Some(RefCell::new(Box::new(writer)));
+        self
+    }
+
+    /// Returns the wrapped transport.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn record(&self, direction: Direction, report: &[u8]) -> crate::Result<()> {
+        let elapsed = self.start.elapsed();
+        if let Some(log) = &self.log {
+            let arrow = match direction {
+                Direction::Out => "->",
+                Direction::In => "<-",
+            };
+            let description = if report.is_empty() {
+                "(timeout)".to_string()
+            } else {
+                describe_report(report)
+            };
+            writeln!(
+                log.borrow_mut(),
+                "[{:>10.3} ms] {arrow} {description}",
+                elapsed.as_secs_f64() * 1000.0
+            )?;
+        }
+        if let Some(capture) = &self.capture {
+            let record = CaptureRecord {
+                time_us: elapsed.as_micros() as u64,
+                direction,
+                report: report.to_vec(),
+            };
+            let mut capture = capture.borrow_mut();
+            serde_json::to_writer(&mut *capture, &record).map_err(std::io::Error::from)?;
+            writeln!(capture)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Transport> Transport for Trace<T> {
+    fn write(&self, report: &[u8]) -> crate::Result<usize> {
+        let len = self.inner.write(report)?;
+        self.record(Direction::Out, report)?;
+        Ok(len)
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout: Option<Duration>) -> crate::Result<usize> {
+        let len = self.inner.read_timeout(buf, timeout)?;
+        self.record(Direction::In, &buf[..len])?;
+        Ok(len)
+    }
+
+    fn usb_info(&self) -> crate::Result<Option<UsbInfo>> {
+        self.inner.usb_info()
+    }
+}
+
+/// Describes a raw report with its fields decoded, flagging anything that
+/// doesn't look like a valid protocol report.
+pub fn describe_report(report: &[u8]) -> String {
+    if report.first() != Some(&REPORT_ID) || report.len() != REPORT_LEN {
+        return format!("other report: {}", hex(report));
+    }
+
+    let cmd = report[1];
+    let len = report[5] as usize;
+    let mut description = match Command::from_u8(cmd) {
+        Some(command) => format!("{command:?}"),
+        None => format!("UnknownCommand({cmd:#04x})"),
+    };
+    write!(
+        description,
+        " error={} address={:#06x} len={} payload=[{}]",
+        report[2],
+        u16::from_be_bytes([report[3], report[4]]),
+        len,
+        hex(&report[6..(6 + len.min(MAX_PAYLOAD_LEN))])
+    )
+    .unwrap();
+    if len > MAX_PAYLOAD_LEN {
+        description.push_str(" (bad length)");
+    }
+    if checksum(&report[..REPORT_LEN - 1]) != report[REPORT_LEN - 1] {
+        description.push_str(" (bad checksum)");
+    }
+    description
+}
+
+/// What is known about a report, to help reverse engineer captured traffic.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReportAnnotation {
+    /// Raw command byte, if the command is unknown.
+    pub unknown_command: Option<u8>,
+
+    /// Known profile settings accessed by a flash read or write.
+    pub fields: Vec<String>,
+
+    /// Flash addresses accessed by a flash read or write that aren't part of
+    /// any known setting.
+    pub unknown_addresses: Vec<Range<usize>>,
+}
+
+/// Annotates a raw report with the known settings it accesses and anything
+/// about it that isn't understood yet.
+pub fn annotate_report(report: &[u8]) -> ReportAnnotation {
+    if report.first() != Some(&REPORT_ID) || report.len() != REPORT_LEN {
+        return ReportAnnotation::default();
+    }
+
+    match Command::from_u8(report[1]) {
+        Some(Command::ReadFlash | Command::WriteFlash) => {
+            let address = u16::from_be_bytes([report[3], report[4]]) as usize;
+            let len = (report[5] as usize).min(MAX_PAYLOAD_LEN);
+            let (fields, unknown_addresses) = flash_fields(address..(address + len));
+            ReportAnnotation {
+                unknown_command: None,
+                fields,
+                unknown_addresses,
+            }
+        }
+        Some(_) => ReportAnnotation::default(),
+        None => ReportAnnotation {
+            unknown_command: Some(report[1]),
+            ..Default::default()
+        },
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub(crate) fn serialize_hex<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex(bytes))
+}
+
+fn deserialize_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    String::deserialize(deserializer)?
+        .split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16).map_err(serde::de::Error::custom))
+        .collect()
+}
@@ -0,0 +1,761 @@
+use super::hid::MAX_PAYLOAD_LEN;
+use super::{
+    action_from_raw, action_to_raw, address, assert_range, checksum, key_event_from_raw,
+    key_event_to_raw, resolution_from_raw, resolution_pair_from_bytes, resolution_pair_to_bytes,
+    resolution_to_raw, BUTTONS, COMBO_SLOT_LEN, MACRO_SLOT_LEN, MAX_COMBO_EVENTS,
+    MAX_MACRO_NAME_LEN, MAX_RESOLUTION_COUNT, NUM_BUTTONS, POLL_RATE_MAP,
+};
+use crate::profile::{
+    Action, Button, Color, KeyEvent, Macro, MacroEvent, MacroMode, Profile, Resolution,
+};
+use crate::{Capabilities, Product};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Length of the flash region used by a single profile.
+pub const PROFILE_FLASH_LEN: usize = address::MACROS + (NUM_BUTTONS * MACRO_SLOT_LEN);
+
+/// Regions holding all settings and button actions (including checksums). The
+/// gaps between them are unused, so each region can be read in one sweep.
+pub(crate) const SETTINGS_REGIONS: [Range<usize>; 3] = [
+    address::POLL_RATE..(address::RESOLUTION_COLORS + (MAX_RESOLUTION_COUNT * 4)),
+    address::BUTTON_ACTIONS..(address::BUTTON_ACTIONS + (NUM_BUTTONS * 4)),
+    address::DEBOUNCE_MS..(address::HIGH_PERFORMANCE + 2),
+];
+
+/// Length of a combo slot that is actually used (count, events, checksum).
+pub(crate) const COMBO_LEN: usize = 1 + (MAX_COMBO_EVENTS * 3) + 1;
+
+/// Length of the start of a macro slot (name length, name, event count).
+pub(crate) const MACRO_HEADER_LEN: usize = 1 + MAX_MACRO_NAME_LEN + 1;
+
+/// Copy of the flash region for a single profile.
+///
+/// Bytes that have not been read from the mouse are left as zero, which is fine
+/// for decoding as long as every region that a profile references was read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlashImage {
+    data: Vec<u8>,
+}
+
+impl FlashImage {
+    /// Creates a blank (zeroed) image.
+    pub fn new() -> Self {
+        Self {
+            data: vec![0; PROFILE_FLASH_LEN],
+        }
+    }
+
+    /// Creates an image from raw flash bytes, which must cover exactly one
+    /// profile.
+    pub fn from_bytes(data: Vec<u8>) -> crate::Result<Self> {
+        if data.len() == PROFILE_FLASH_LEN {
+            Ok(Self { data })
+        } else {
+            Err(crate::Error::InvalidProfile(format!(
+                "Flash image is {} bytes, expected {PROFILE_FLASH_LEN}",
+                data.len()
+            )))
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Copies `data` into the image at `address`.
+    pub(crate) fn fill(&mut self, address: usize, data: &[u8]) {
+        self.data[address..(address + data.len())].copy_from_slice(data);
+    }
+
+    /// Returns `length` bytes at `address` after verifying the checksum byte
+    /// that follows them.
+    fn read_checked(&self, address: usize, length: usize) -> crate::Result<&[u8]> {
+        let end = address + length;
+        let data = self.data.get(address..=end).ok_or_else(|| {
+            crate::Error::InvalidProfile(format!("Address {address} is outside of profile flash"))
+        })?;
+        if checksum(data) == 0 {
+            Ok(&data[..length])
+        } else {
+            Err(crate::Error::InvalidProfile(format!(
+                "Checksum mismatch at {address:04X}: {data:?}"
+            )))
+        }
+    }
+
+    fn read_byte(&self, address: usize) -> crate::Result<u8> {
+        Ok(self.read_checked(address, 1)?[0])
+    }
+
+    fn read_bool(&self, address: usize) -> crate::Result<bool> {
+        Ok(self.read_byte(address)? != 0)
+    }
+}
+
+/// Returns the ranges within `regions` where `a` and `b` differ, to be written
+/// in reports of up to [`MAX_PAYLOAD_LEN`] bytes.
+///
+/// Changes in the same region are merged across unchanged bytes as long as that
+/// doesn't take more reports, since rewriting a few bytes with the same values
+/// is cheaper than sending another report.
+pub(crate) fn changed_ranges(
+    a: &FlashImage,
+    b: &FlashImage,
+    regions: &[Range<usize>],
+) -> Vec<Range<usize>> {
+    let reports = |len: usize| len.div_ceil(MAX_PAYLOAD_LEN);
+    let mut changed: Vec<Range<usize>> = Vec::new();
+    for region in regions {
+        let mut region_changed: Vec<Range<usize>> = Vec::new();
+        for address in region.clone() {
+            if a.data[address] == b.data[address] {
+                continue;
+            }
+            match region_changed.last_mut() {
+                Some(last) if last.end == address => last.end += 1,
+                _ => region_changed.push(address..(address + 1)),
+            }
+        }
+
+        let first = changed.len();
+        for range in region_changed {
+            match changed[first..].last_mut() {
+                Some(last)
+                    if reports(range.end - last.start)
+                        <= reports(last.len()) + reports(range.len()) =>
+                {
+                    last.end = range.end
+                }
+                _ => changed.push(range),
+            }
+        }
+    }
+    changed
+}
+
+/// Known fields of a profile's flash as (name, address, length of each
+/// element including its checksum, number of elements).
+const FLASH_FIELDS: [(&str, usize, usize, usize); 16] = [
+    ("poll_rate", address::POLL_RATE, 2, 1),
+    ("resolution_count", address::RESOLUTION_COUNT, 2, 1),
+    ("current_resolution_index", address::RESOLUTION_INDEX, 2, 1),
+    ("lift_off_distance", address::LIFT_OFF_DISTANCE, 2, 1),
+    ("resolutions", address::RESOLUTIONS, 4, MAX_RESOLUTION_COUNT),
+    (
+        "resolution_colors",
+        address::RESOLUTION_COLORS,
+        4,
+        MAX_RESOLUTION_COUNT,
+    ),
+    ("button_actions", address::BUTTON_ACTIONS, 4, NUM_BUTTONS),
+    ("debounce_ms", address::DEBOUNCE_MS, 2, 1),
+    ("motion_sync", address::MOTION_SYNC, 2, 1),
+    ("angle_snapping", address::ANGLE_SNAPPING, 2, 1),
+    ("ripple_control", address::RIPPLE_CONTROL, 2, 1),
+    ("peak_performance", address::PEAK_PERFORMANCE, 2, 1),
+    (
+        "peak_performance_time",
+        address::PEAK_PERFORMANCE_TIME,
+        2,
+        1,
+    ),
+    ("high_performance", address::HIGH_PERFORMANCE, 2, 1),
+    ("combos", address::COMBOS, COMBO_SLOT_LEN, NUM_BUTTONS),
+    ("macros", address::MACROS, MACRO_SLOT_LEN, NUM_BUTTONS),
+];
+
+/// Names the known fields overlapping `range` of a profile's flash (e.g.
+/// `resolutions[1]`), along with the parts of it that aren't a known field.
+pub(crate) fn flash_fields(range: Range<usize>) -> (Vec<String>, Vec<Range<usize>>) {
+    let mut fields: Vec<String> = Vec::new();
+    let mut unknown: Vec<Range<usize>> = Vec::new();
+    for address in range {
+        let field = FLASH_FIELDS
+            .iter()
+            .find(|(_, start, len, count)| (*start..(start + len * count)).contains(&address));
+        match field {
+            Some((name, start, len, count)) => {
+                let name = if *count == 1 {
+                    name.to_string()
+                } else {
+                    format!("{}[{}]", name, (address - start) / len)
+                };
+                if fields.last() != Some(&name) {
+                    fields.push(name);
+                }
+            }
+            None => match unknown.last_mut() {
+                Some(last) if last.end == address => last.end += 1,
+                _ => unknown.push(address..(address + 1)),
+            },
+        }
+    }
+    (fields, unknown)
+}
+
+impl Default for FlashImage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decodes a full profile from a flash image.
+///
+/// Settings that `product` doesn't support are left out of the profile.
+pub fn decode_profile(image: &FlashImage, product: Product) -> crate::Result<Profile> {
+    let capabilities = product.capabilities();
+    let (button_map, macros) = button_mappings(image, &capabilities)?;
+    let resolution_count = resolution_count(image, &capabilities)?;
+    let feature = |supported: bool, address: usize| -> crate::Result<Option<bool>> {
+        if supported {
+            image.read_bool(address).map(Some)
+        } else {
+            Ok(None)
+        }
+    };
+    let peak_performance_time = if capabilities.peak_performance {
+        Some(image.read_byte(address::PEAK_PERFORMANCE_TIME)? as u16 * 10)
+    } else {
+        None
+    };
+    Ok(Profile {
+        poll_rate: Some(poll_rate(image)?),
+        current_resolution_index: Some(resolution_index(image, &capabilities)? as usize),
+        resolutions: resolutions(image, &capabilities, resolution_count)?,
+        resolution_colors: resolution_colors(image, resolution_count)?,
+        lift_off_distance: Some(lift_off_distance(image, &capabilities)?),
+        debounce_ms: Some(debounce_ms(image, &capabilities)?),
+        motion_sync: feature(capabilities.motion_sync, address::MOTION_SYNC)?,
+        angle_snapping: feature(capabilities.angle_snapping, address::ANGLE_SNAPPING)?,
+        ripple_control: feature(capabilities.ripple_control, address::RIPPLE_CONTROL)?,
+        peak_performance: feature(capabilities.peak_performance, address::PEAK_PERFORMANCE)?,
+        peak_performance_time,
+        high_performance: feature(capabilities.high_performance, address::HIGH_PERFORMANCE)?,
+        button_map,
+        macros,
+    })
+}
+
+fn poll_rate(image: &FlashImage) -> crate::Result<u16> {
+    let raw = image.read_byte(address::POLL_RATE)?;
+    Ok(POLL_RATE_MAP
+        .iter()
+        .find(|(_, r)| *r == raw)
+        .ok_or(crate::Error::InvalidProfile(format!(
+            "Invalid poll rate value '{}' from mouse",
+            raw
+        )))?
+        .0)
+}
+
+fn resolution_index(image: &FlashImage, capabilities: &Capabilities) -> crate::Result<u8> {
+    let raw = image.read_byte(address::RESOLUTION_INDEX)?;
+    assert_range(0..capabilities.max_resolution_count, raw)?;
+    Ok(raw)
+}
+
+fn resolution_count(image: &FlashImage, capabilities: &Capabilities) -> crate::Result<usize> {
+    let raw = image.read_byte(address::RESOLUTION_COUNT)?;
+    assert_range(1..=capabilities.max_resolution_count, raw)?;
+    Ok(raw as usize)
+}
+
+fn resolutions(
+    image: &FlashImage,
+    capabilities: &Capabilities,
+    count: usize,
+) -> crate::Result<Vec<Resolution>> {
+    (0..count)
+        .map(|i| {
+            let raw = image.read_checked(address::RESOLUTIONS + (i * 4), 3)?;
+            let (x, y) = resolution_pair_from_bytes(raw);
+            Ok(Resolution::new(
+                resolution_from_raw(capabilities, x)?,
+                resolution_from_raw(capabilities, y)?,
+            ))
+        })
+        .collect()
+}
+
+fn resolution_colors(image: &FlashImage, count: usize) -> crate::Result<Vec<Color>> {
+    (0..count)
+        .map(|i| {
+            let raw = image.read_checked(address::RESOLUTION_COLORS + (i * 4), 3)?;
+            Ok(Color::from_bytes(raw))
+        })
+        .collect()
+}
+
+fn lift_off_distance(image: &FlashImage, capabilities: &Capabilities) -> crate::Result<u8> {
+    let raw = image.read_byte(address::LIFT_OFF_DISTANCE)?;
+    assert_lift_off_distance(capabilities, raw)?;
+    Ok(raw)
+}
+
+fn assert_lift_off_distance(capabilities: &Capabilities, val: u8) -> crate::Result<()> {
+    if capabilities.lift_off_distances.contains(&val) {
+        Ok(())
+    } else {
+        Err(crate::Error::InvalidProfile(format!(
+            "Lift off distance '{val}' is not one of {:?}",
+            capabilities.lift_off_distances
+        )))
+    }
+}
+
+fn debounce_ms(image: &FlashImage, capabilities: &Capabilities) -> crate::Result<u8> {
+    let raw = image.read_byte(address::DEBOUNCE_MS)?;
+    assert_range(0..=capabilities.max_debounce_ms, raw)?;
+    Ok(raw)
+}
+
+fn button_mappings(
+    image: &FlashImage,
+    capabilities: &Capabilities,
+) -> crate::Result<(HashMap<Button, Action>, HashMap<String, Macro>)> {
+    let mut button_map = HashMap::new();
+    let mut macros = HashMap::new();
+    for (i, button) in BUTTONS.iter().enumerate() {
+        if !capabilities.buttons.contains(button) {
+            continue;
+        }
+        let action_raw = image.read_checked(address::BUTTON_ACTIONS + (i * 4), 3)?;
+        match action_raw {
+            [5, 0, 0] => {
+                button_map.insert(
+                    *button,
+                    Action::Combo {
+                        events: key_combo(image, capabilities, i)?,
+                    },
+                );
+            }
+
+            [6, macro_index, repeat] => {
+                if *macro_index as usize != i {
+                    eprintln!(
+                        "Macro index ({macro_index}) doesn't match button ({i}). Corrupted action?"
+                    );
+                }
+                let mode = match repeat {
+                    255 => MacroMode::UntilPress,
+                    254 => MacroMode::Hold,
+                    253 => MacroMode::Toggle,
+                    x => MacroMode::Repeat(*x),
+                };
+                let (name, events) = get_macro(image, capabilities, i)?;
+                macros.insert(name.clone(), Macro { mode, events });
+                button_map.insert(*button, Action::Macro { name });
+            }
+
+            _ => {
+                button_map.insert(*button, action_from_raw(capabilities, action_raw)?);
+            }
+        }
+    }
+    Ok((button_map, macros))
+}
+
+fn key_combo(
+    image: &FlashImage,
+    capabilities: &Capabilities,
+    index: usize,
+) -> crate::Result<Vec<KeyEvent>> {
+    assert_range(0..NUM_BUTTONS, index)?;
+    let address = address::COMBOS + (index * COMBO_SLOT_LEN);
+    let len = image.as_bytes()[address] as usize;
+    assert_range(1..=capabilities.max_combo_events, len)?;
+    let data = image.read_checked(address, (len * 3) + 1)?;
+    data[1..].chunks_exact(3).map(key_event_from_raw).collect()
+}
+
+fn get_macro(
+    image: &FlashImage,
+    capabilities: &Capabilities,
+    index: usize,
+) -> crate::Result<(String, Vec<MacroEvent>)> {
+    assert_range(0..NUM_BUTTONS, index)?;
+
+    let mut address = address::MACROS + (index * MACRO_SLOT_LEN);
+    let name_len = image.as_bytes()[address] as usize;
+    assert_range(1..=capabilities.max_macro_name_len, name_len)?;
+    address += 1;
+
+    let name =
+        String::from_utf8_lossy(&image.as_bytes()[address..(address + name_len)]).to_string();
+    address += MAX_MACRO_NAME_LEN;
+
+    let events_len = image.as_bytes()[address] as usize;
+    assert_range(1..=capabilities.max_macro_events, events_len)?;
+    let events_bytes = image.read_checked(address, (events_len * 5) + 1)?;
+
+    let mut events = Vec::new();
+    for raw in events_bytes[1..].chunks_exact(5) {
+        events.push(MacroEvent {
+            key_event: key_event_from_raw(&raw[..3])?,
+            delay_ms: u16::from_be_bytes([raw[3], raw[4]]),
+        });
+    }
+
+    Ok((name, events))
+}
+
+/// Encodes a profile into a blank flash image.
+///
+/// Fields that are not set in `profile` are left blank, so only complete
+/// profiles produce images that can be decoded again. Use
+/// [`encode_profile_into`] to apply a partial profile to an existing image.
+pub fn encode_profile(profile: &Profile, product: Product) -> crate::Result<FlashImage> {
+    let mut image = FlashImage::new();
+    encode_profile_into(profile, product, &mut image)?;
+    Ok(image)
+}
+
+/// Encodes the fields that are set in `profile` into `image`, leaving all other
+/// bytes untouched. Returns the sorted, non-overlapping address ranges written.
+///
+/// Settings that `product` doesn't support are rejected.
+pub fn encode_profile_into(
+    profile: &Profile,
+    product: Product,
+    image: &mut FlashImage,
+) -> crate::Result<Vec<Range<usize>>> {
+    let capabilities = product.capabilities();
+    let mut writer = ImageWriter {
+        image,
+        written: Vec::new(),
+    };
+    let assert_supported = |supported: bool, setting: &str| {
+        if supported {
+            Ok(())
+        } else {
+            Err(crate::Error::InvalidProfile(format!(
+                "{setting} is not supported by the {product}"
+            )))
+        }
+    };
+
+    if let Some(val) = profile.poll_rate {
+        set_poll_rate(&mut writer, &capabilities, val)?;
+    }
+    if let Some(val) = profile.current_resolution_index {
+        assert_range(0..capabilities.max_resolution_count, val)?;
+        writer.write_byte(address::RESOLUTION_INDEX, val as u8);
+    }
+    if !profile.resolutions.is_empty() {
+        set_resolutions(&mut writer, &capabilities, &profile.resolutions)?;
+    }
+    if !profile.resolution_colors.is_empty() {
+        set_resolution_colors(&mut writer, &capabilities, &profile.resolution_colors)?;
+    }
+    if let Some(val) = profile.lift_off_distance {
+        assert_lift_off_distance(&capabilities, val)?;
+        writer.write_byte(address::LIFT_OFF_DISTANCE, val);
+    }
+    if let Some(val) = profile.debounce_ms {
+        assert_range(0..=capabilities.max_debounce_ms, val)?;
+        writer.write_byte(address::DEBOUNCE_MS, val);
+    }
+    if let Some(val) = profile.motion_sync {
+        assert_supported(capabilities.motion_sync, "Motion sync")?;
+        writer.write_bool(address::MOTION_SYNC, val);
+    }
+    if let Some(val) = profile.angle_snapping {
+        assert_supported(capabilities.angle_snapping, "Angle snapping")?;
+        writer.write_bool(address::ANGLE_SNAPPING, val);
+    }
+    if let Some(val) = profile.ripple_control {
+        assert_supported(capabilities.ripple_control, "Ripple control")?;
+        writer.write_bool(address::RIPPLE_CONTROL, val);
+    }
+    if let Some(val) = profile.peak_performance {
+        assert_supported(capabilities.peak_performance, "Peak performance")?;
+        writer.write_bool(address::PEAK_PERFORMANCE, val);
+    }
+    if let Some(val) = profile.peak_performance_time {
+        assert_supported(capabilities.peak_performance, "Peak performance")?;
+        let raw = (val / 10).min(u8::MAX as u16) as u8;
+        writer.write_byte(address::PEAK_PERFORMANCE_TIME, raw);
+    }
+    if let Some(val) = profile.high_performance {
+        assert_supported(capabilities.high_performance, "High performance")?;
+        writer.write_bool(address::HIGH_PERFORMANCE, val);
+    }
+    if let Some(button) = profile
+        .button_map
+        .keys()
+        .find(|button| !capabilities.buttons.contains(button))
+    {
+        return Err(crate::Error::InvalidProfile(format!(
+            "{button:?} button is not supported by the {product}"
+        )));
+    }
+    if !profile.button_map.is_empty() {
+        set_button_mappings(
+            &mut writer,
+            &capabilities,
+            &profile.button_map,
+            &profile.macros,
+        )?;
+    }
+
+    let mut written = writer.written;
+    written.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<usize>> = Vec::new();
+    for range in written {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    Ok(merged)
+}
+
+/// Writes encoded fields into a flash image, keeping track of the ranges written.
+struct ImageWriter<'a> {
+    image: &'a mut FlashImage,
+    written: Vec<Range<usize>>,
+}
+
+impl ImageWriter<'_> {
+    fn write(&mut self, address: usize, data: &[u8]) {
+        self.image.fill(address, data);
+        self.written.push(address..(address + data.len()));
+    }
+
+    /// Writes `data` followed by its checksum.
+    fn write_checked(&mut self, address: usize, data: &[u8]) {
+        let mut data = data.to_vec();
+        data.push(checksum(&data));
+        self.write(address, &data);
+    }
+
+    fn write_byte(&mut self, address: usize, val: u8) {
+        self.write_checked(address, &[val]);
+    }
+
+    fn write_bool(&mut self, address: usize, val: bool) {
+        self.write_byte(address, val as u8);
+    }
+}
+
+fn set_poll_rate(
+    writer: &mut ImageWriter,
+    capabilities: &Capabilities,
+    mut poll_rate: u16,
+) -> crate::Result<()> {
+    if poll_rate > capabilities.max_poll_rate() {
+        eprintln!(
+            "Warning: Desired poll rate is unsupported by mouse. Reducing to {}Hz.",
+            capabilities.max_poll_rate()
+        );
+        poll_rate = capabilities.max_poll_rate();
+    }
+    let raw = POLL_RATE_MAP
+        .iter()
+        .find(|(p, _)| *p == poll_rate && capabilities.poll_rates.contains(p))
+        .ok_or(crate::Error::InvalidProfile(format!(
+            "Poll rate {poll_rate} is not supported",
+        )))?
+        .1;
+    writer.write_byte(address::POLL_RATE, raw);
+    Ok(())
+}
+
+fn set_resolutions(
+    writer: &mut ImageWriter,
+    capabilities: &Capabilities,
+    resolutions: &[Resolution],
+) -> crate::Result<()> {
+    assert_range(1..=capabilities.max_resolution_count, resolutions.len())?;
+    writer.write_byte(address::RESOLUTION_COUNT, resolutions.len() as u8);
+    for (i, resolution) in resolutions.iter().enumerate() {
+        writer.write_checked(
+            address::RESOLUTIONS + (i * 4),
+            &resolution_pair_to_bytes(
+                resolution_to_raw(capabilities, resolution.x)?,
+                resolution_to_raw(capabilities, resolution.y)?,
+            ),
+        );
+    }
+    Ok(())
+}
+
+fn set_resolution_colors(
+    writer: &mut ImageWriter,
+    capabilities: &Capabilities,
+    colors: &[Color],
+) -> crate::Result<()> {
+    assert_range(1..=capabilities.max_resolution_count, colors.len())?;
+    for (i, color) in colors.iter().enumerate() {
+        writer.write_checked(address::RESOLUTION_COLORS + (i * 4), &color.to_bytes());
+    }
+    Ok(())
+}
+
+fn set_button_mappings(
+    writer: &mut ImageWriter,
+    capabilities: &Capabilities,
+    button_map: &HashMap<Button, Action>,
+    macros: &HashMap<String, Macro>,
+) -> crate::Result<()> {
+    for (i, button) in BUTTONS.iter().enumerate() {
+        let Some(action) = button_map.get(button) else {
+            continue;
+        };
+        let action_raw = match action {
+            Action::Combo { events } => {
+                set_key_combo(writer, capabilities, i, events)?;
+                action_to_raw(capabilities, action)?
+            }
+            Action::Macro { name } => {
+                let m = macros
+                    .get(name)
+                    .ok_or(crate::Error::InvalidProfile(format!(
+                        "Undefined reference to macro: {name}"
+                    )))?;
+                set_macro(writer, capabilities, i, name, &m.events)?;
+                [
+                    6,
+                    i as u8,
+                    match m.mode {
+                        MacroMode::Repeat(x) => x,
+                        MacroMode::Toggle => 253,
+                        MacroMode::Hold => 254,
+                        MacroMode::UntilPress => 255,
+                    },
+                ]
+            }
+            _ => action_to_raw(capabilities, action)?,
+        };
+        writer.write_checked(address::BUTTON_ACTIONS + (i * 4), &action_raw);
+    }
+    Ok(())
+}
+
+fn set_key_combo(
+    writer: &mut ImageWriter,
+    capabilities: &Capabilities,
+    index: usize,
+    key_events: &[KeyEvent],
+) -> crate::Result<()> {
+    assert_range(0..NUM_BUTTONS, index)?;
+    assert_range(1..=capabilities.max_combo_events, key_events.len())?;
+    let mut data = vec![key_events.len() as u8];
+    for key_event in key_events {
+        data.extend_from_slice(&key_event_to_raw(key_event)?);
+    }
+    writer.write_checked(address::COMBOS + (index * COMBO_SLOT_LEN), &data);
+    Ok(())
+}
+
+fn set_macro(
+    writer: &mut ImageWriter,
+    capabilities: &Capabilities,
+    index: usize,
+    name: &str,
+    macro_events: &[MacroEvent],
+) -> crate::Result<()> {
+    assert_range(0..NUM_BUTTONS, index)?;
+    let mut address = address::MACROS + (index * MACRO_SLOT_LEN);
+
+    // The name isn't checksummed.
+    assert_range(1..=capabilities.max_macro_name_len, name.len())?;
+    let mut buf = vec![name.len() as u8];
+    buf.extend(name.as_bytes());
+    writer.write(address, &buf);
+    address += 1 + MAX_MACRO_NAME_LEN;
+
+    assert_range(1..=capabilities.max_macro_events, macro_events.len())?;
+    let mut buf = vec![macro_events.len() as u8];
+    for event in macro_events {
+        buf.extend(key_event_to_raw(&event.key_event)?);
+        buf.extend(u16::to_be_bytes(event.delay_ms));
+    }
+    writer.write_checked(address, &buf);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_resolutions(resolutions: &[(u16, u16)]) -> crate::Result<FlashImage> {
+        let profile = Profile {
+            resolutions: resolutions
+                .iter()
+                .map(|&(x, y)| Resolution::new(x, y))
+                .collect(),
+            ..Profile::default()
+        };
+        let mut image = FlashImage::new();
+        encode_profile_into(&profile, Product::AtlantisWireless4K, &mut image)?;
+        Ok(image)
+    }
+
+    fn resolution_slot(image: &FlashImage, index: usize) -> &[u8] {
+        let start = address::RESOLUTIONS + (index * 4);
+        &image.as_bytes()[start..(start + 3)]
+    }
+
+    #[test]
+    fn resolutions_up_to_12800_leave_high_bits_clear() {
+        let image = encode_resolutions(&[(800, 1600), (12800, 50)]).unwrap();
+        assert_eq!(resolution_slot(&image, 0), [15, 31, 0]);
+        assert_eq!(resolution_slot(&image, 1), [255, 0, 0]);
+    }
+
+    #[test]
+    fn resolutions_above_12800_use_high_bits() {
+        // Raw 519 (0x207) for X and 259 (0x103) for Y.
+        let image = encode_resolutions(&[(26000, 13000)]).unwrap();
+        assert_eq!(resolution_slot(&image, 0), [0x07, 0x03, 0b0100_1000]);
+    }
+
+    #[test]
+    fn resolutions_round_trip() {
+        let input = [(50, 26000), (12850, 12800), (25950, 400)];
+        let image = encode_resolutions(&input).unwrap();
+        let capabilities = Product::AtlantisWireless4K.capabilities();
+        let decoded = resolutions(&image, &capabilities, input.len()).unwrap();
+        let decoded: Vec<_> = decoded.iter().map(|r| (r.x, r.y)).collect();
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    fn invalid_resolutions_are_rejected() {
+        assert!(encode_resolutions(&[(26050, 800)]).is_err());
+        assert!(encode_resolutions(&[(800, 825)]).is_err());
+        assert!(encode_resolutions(&[(0, 800)]).is_err());
+    }
+
+    #[test]
+    fn changed_ranges_merges_nearby_changes() {
+        let a = FlashImage::new();
+        let mut b = FlashImage::new();
+        for address in [0, 1, 5, 6, 15, 40] {
+            b.fill(address, &[1]);
+        }
+        // 0..16 fits in 2 reports like 0..7 and 15..16 would, but merging 40
+        // as well would take 5 reports instead of 3.
+        assert_eq!(changed_ranges(&a, &b, &SETTINGS_REGIONS), [0..16, 40..41]);
+    }
+
+    #[test]
+    fn changed_ranges_doesnt_merge_across_regions() {
+        let a = FlashImage::new();
+        let mut b = FlashImage::new();
+        let last = SETTINGS_REGIONS[0].end - 1;
+        let next = SETTINGS_REGIONS[1].start;
+        b.fill(last, &[1]);
+        b.fill(next, &[1]);
+        assert_eq!(
+            changed_ranges(&a, &b, &SETTINGS_REGIONS),
+            [last..(last + 1), next..(next + 1)]
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use super::{
+    flash_fields, raw_request, read_flash, serialize_hex, Atlantis, Command, MAX_PAYLOAD_LEN,
+    PROFILE_FLASH_LEN,
+};
+use crate::{Product, Transport};
+use serde::Serialize;
+use std::ops::Range;
+
+/// End of the flash scanned after the macro area. The real flash size is
+/// unknown, so this is just a guess at a sensible limit.
+const PROBE_FLASH_END: usize = 0x1000;
+
+/// Results of probing a mouse for unknown commands and flash contents, to be
+/// attached to issues when adding support for other mice.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeReport {
+    pub product: Product,
+
+    /// Index of the profile whose flash was scanned.
+    pub profile: usize,
+
+    pub commands: Vec<CommandProbe>,
+    pub flash: Vec<FlashProbe>,
+}
+
+/// How the mouse answered a probe.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum ProbeOutcome {
+    /// Responded without an error.
+    Data,
+
+    /// Responded with an error code.
+    Error(u8),
+
+    /// Didn't respond in time.
+    NoResponse,
+
+    /// Responded with something invalid.
+    Invalid(String),
+}
+
+/// Result of sending an unknown command.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandProbe {
+    pub command: u8,
+    pub outcome: ProbeOutcome,
+
+    /// Raw response report, if there was one.
+    #[serde(serialize_with = "serialize_hex")]
+    pub response: Vec<u8>,
+}
+
+/// Result of reading a flash range that isn't mapped to any known setting.
+#[derive(Debug, Clone, Serialize)]
+pub struct FlashProbe {
+    pub start: usize,
+    pub end: usize,
+
+    /// Outcome of the read that stopped the scan, or `Data` if the whole
+    /// range could be read.
+    pub outcome: ProbeOutcome,
+
+    /// Bytes read from the start of the range.
+    #[serde(serialize_with = "serialize_hex")]
+    pub data: Vec<u8>,
+}
+
+impl<T: Transport> Atlantis<T> {
+    /// Probes the active profile for unknown commands and for the contents of
+    /// unmapped flash.
+    ///
+    /// Each unknown command is sent with an empty payload. Known reads have
+    /// even command bytes and their writes odd ones, so odd commands are only
+    /// sent if `odd_commands` is set.
+    pub fn probe(&self, odd_commands: bool) -> crate::Result<ProbeReport> {
+        let commands = (0..=u8::MAX)
+            .filter(|cmd| Command::from_u8(*cmd).is_none() && (odd_commands || cmd % 2 == 0))
+            .map(|cmd| self.probe_command(cmd))
+            .collect::<crate::Result<_>>()?;
+
+        let (_, mut ranges) = flash_fields(0..PROFILE_FLASH_LEN);
+        ranges.push(PROFILE_FLASH_LEN..PROBE_FLASH_END);
+        let flash = ranges
+            .into_iter()
+            .map(|range| self.probe_flash(range))
+            .collect::<crate::Result<_>>()?;
+
+        Ok(ProbeReport {
+            product: self.product,
+            profile: crate::Mouse::active_profile(self)?,
+            commands,
+            flash,
+        })
+    }
+
+    fn probe_command(&self, cmd: u8) -> crate::Result<CommandProbe> {
+        let (outcome, response) = match raw_request(&self.device, &self.policy, cmd, 0, &[]) {
+            Ok(response) if response[2] == 0 => (ProbeOutcome::Data, response),
+            Ok(response) => (ProbeOutcome::Error(response[2]), response),
+            Err(e) => (probe_failure(e)?, Vec::new()),
+        };
+        Ok(CommandProbe {
+            command: cmd,
+            outcome,
+            response,
+        })
+    }
+
+    fn probe_flash(&self, range: Range<usize>) -> crate::Result<FlashProbe> {
+        let mut data = Vec::new();
+        let mut outcome = ProbeOutcome::Data;
+        while data.len() < range.len() {
+            let address = range.start + data.len();
+            let len = (range.len() - data.len()).min(MAX_PAYLOAD_LEN);
+            match read_flash(&self.device, &self.policy, address, len) {
+                Ok(mut chunk) => data.append(&mut chunk),
+                Err(e) => {
+                    outcome = probe_failure(e)?;
+                    break;
+                }
+            }
+        }
+        Ok(FlashProbe {
+            start: range.start,
+            end: range.end,
+            outcome,
+            data,
+        })
+    }
+}
+
+/// Converts an error from a probe into its outcome, or passes it on if it
+/// isn't caused by the mouse's response (e.g. the device was unplugged).
+fn probe_failure(error: crate::Error) -> crate::Result<ProbeOutcome> {
+    match error {
+        crate::Error::MouseErrorResponse(code) => Ok(ProbeOutcome::Error(code)),
+        crate::Error::Timeout | crate::Error::DeviceAsleep => Ok(ProbeOutcome::NoResponse),
+        e @ (crate::Error::BadChecksum(_)
+        | crate::Error::MalformedReport(_)
+        | crate::Error::ShortRead { .. }) => Ok(ProbeOutcome::Invalid(e.to_string())),
+        e => Err(e),
+    }
+}
@@ -0,0 +1,70 @@
+use crate::{BatteryStatus, ChargeState};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Battery status at a point in time, as stored in a history file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatterySample {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+
+    pub millivolts: u16,
+    pub percentage: u8,
+    pub charge_state: ChargeState,
+}
+
+impl BatterySample {
+    pub fn new(time: u64, status: &BatteryStatus) -> Self {
+        Self {
+            time,
+            millivolts: status.millivolts,
+            percentage: status.percentage,
+            charge_state: status.charge_state,
+        }
+    }
+}
+
+/// Estimates the remaining runtime from the discharge slope of the most
+/// recent samples, which must be in time order.
+///
+/// Only samples since the battery was last charged are used. Returns `None`
+/// if there isn't enough history or the battery isn't discharging.
+pub fn estimate_runtime(samples: &[BatterySample]) -> Option<Duration> {
+    let last = samples.last()?;
+    if last.charge_state != ChargeState::Discharging {
+        return None;
+    }
+
+    // Walk back to the start of the current discharge. A rise in charge means
+    // the battery was charged in between, even if it wasn't sampled.
+    let mut start = samples.len() - 1;
+    while start > 0 {
+        let (earlier, later) = (&samples[start - 1], &samples[start]);
+        if earlier.charge_state != ChargeState::Discharging || earlier.percentage < later.percentage
+        {
+            break;
+        }
+        start -= 1;
+    }
+    let discharge = &samples[start..];
+    if discharge.len() < 2 {
+        return None;
+    }
+
+    // Least squares slope of percentage over time.
+    let count = discharge.len() as f64;
+    let mean_time = discharge.iter().map(|s| s.time as f64).sum::<f64>() / count;
+    let mean_percentage = discharge.iter().map(|s| s.percentage as f64).sum::<f64>() / count;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for sample in discharge {
+        let time = sample.time as f64 - mean_time;
+        covariance += time * (sample.percentage as f64 - mean_percentage);
+        variance += time * time;
+    }
+    let slope = covariance / variance;
+    if !slope.is_finite() || slope >= 0.0 {
+        return None;
+    }
+
+    Some(Duration::from_secs_f64(last.percentage as f64 / -slope))
+}
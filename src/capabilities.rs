@@ -0,0 +1,139 @@
+use crate::profile::{Button, Profile};
+use serde::Serialize;
+
+/// Settings and limits supported by a product, which profiles are validated
+/// against before being written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Capabilities {
+    /// Supported poll rates in Hz, in ascending order.
+    pub poll_rates: &'static [u16],
+
+    pub min_resolution: u16,
+    pub max_resolution: u16,
+
+    /// Resolutions must be a multiple of this.
+    pub resolution_step: u16,
+
+    /// Maximum number of resolution stages in a profile.
+    pub max_resolution_count: usize,
+
+    /// Supported lift off distance settings.
+    pub lift_off_distances: &'static [u8],
+
+    pub max_debounce_ms: u8,
+
+    /// Physical buttons that can be remapped.
+    pub buttons: &'static [Button],
+
+    /// Maximum number of key events in a combo.
+    pub max_combo_events: usize,
+
+    pub max_macro_name_len: usize,
+
+    /// Maximum number of key events in a macro.
+    pub max_macro_events: usize,
+
+    pub motion_sync: bool,
+    pub angle_snapping: bool,
+    pub ripple_control: bool,
+
+    /// Peak performance mode and its timeout.
+    pub peak_performance: bool,
+
+    pub high_performance: bool,
+
+    /// Battery discharge curve as (millivolts, percentage), in ascending order.
+    /// Percentages are interpolated between points.
+    pub discharge_curve: &'static [(u16, u8)],
+}
+
+impl Capabilities {
+    /// Returns the highest supported poll rate.
+    pub fn max_poll_rate(&self) -> u16 {
+        self.poll_rates.last().copied().unwrap_or_default()
+    }
+
+    /// Clears the settings of `profile` that aren't supported, e.g. feature
+    /// toggles and buttons the product doesn't have.
+    pub fn strip_unsupported(&self, profile: &mut Profile) {
+        if !self.motion_sync {
+            profile.motion_sync = None;
+        }
+        if !self.angle_snapping {
+            profile.angle_snapping = None;
+        }
+        if !self.ripple_control {
+            profile.ripple_control = None;
+        }
+        if !self.peak_performance {
+            profile.peak_performance = None;
+            profile.peak_performance_time = None;
+        }
+        if !self.high_performance {
+            profile.high_performance = None;
+        }
+        profile
+            .button_map
+            .retain(|button, _| self.buttons.contains(button));
+    }
+}
+
+/// Typical discharge curve of a single Li-ion cell, which all known Lamzu mice
+/// use. It hasn't been compared with the official software for any model.
+const LI_ION_DISCHARGE_CURVE: &[(u16, u8)] = &[
+    (3050, 0),
+    (3610, 5),
+    (3690, 10),
+    (3710, 15),
+    (3730, 20),
+    (3750, 25),
+    (3770, 30),
+    (3790, 35),
+    (3800, 40),
+    (3820, 45),
+    (3840, 50),
+    (3850, 55),
+    (3870, 60),
+    (3910, 65),
+    (3950, 70),
+    (3980, 75),
+    (4020, 80),
+    (4080, 85),
+    (4110, 90),
+    (4150, 95),
+    (4200, 100),
+];
+
+/// Lamzu Atlantis Mini Pro, wired or with a 1K receiver.
+pub(crate) const ATLANTIS: Capabilities = Capabilities {
+    poll_rates: &[125, 250, 500, 1000],
+    min_resolution: 50,
+    max_resolution: 26000,
+    resolution_step: 50,
+    max_resolution_count: 8,
+    lift_off_distances: &[1, 2],
+    max_debounce_ms: 15,
+    buttons: &[
+        Button::Left,
+        Button::Right,
+        Button::Middle,
+        Button::Back,
+        Button::Forward,
+        Button::Bottom,
+    ],
+    max_combo_events: 6,
+    max_macro_name_len: 30,
+    max_macro_events: 70,
+    motion_sync: true,
+    angle_snapping: true,
+    ripple_control: true,
+    peak_performance: true,
+    high_performance: true,
+    discharge_curve: LI_ION_DISCHARGE_CURVE,
+};
+
+/// Lamzu Atlantis Mini Pro with a 4K receiver.
+pub(crate) const ATLANTIS_4K: Capabilities = Capabilities {
+    poll_rates: &[125, 250, 500, 1000, 2000, 4000],
+    ..ATLANTIS
+};
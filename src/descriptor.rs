@@ -0,0 +1,374 @@
+use hidapi::HidDevice;
+use serde::Serialize;
+
+/// Usage pages from this value up are vendor defined.
+pub const VENDOR_USAGE_PAGE_MIN: u16 = 0xFF00;
+
+const LONG_ITEM_PREFIX: u8 = 0b1111_1110;
+
+// Item types.
+const ITEM_MAIN: u8 = 0;
+const ITEM_GLOBAL: u8 = 1;
+const ITEM_LOCAL: u8 = 2;
+
+// Main item tags.
+const MAIN_INPUT: u8 = 0x8;
+const MAIN_OUTPUT: u8 = 0x9;
+const MAIN_COLLECTION: u8 = 0xA;
+const MAIN_FEATURE: u8 = 0xB;
+const MAIN_END_COLLECTION: u8 = 0xC;
+
+// Global item tags.
+const GLOBAL_USAGE_PAGE: u8 = 0x0;
+const GLOBAL_REPORT_SIZE: u8 = 0x7;
+const GLOBAL_REPORT_ID: u8 = 0x8;
+const GLOBAL_REPORT_COUNT: u8 = 0x9;
+const GLOBAL_PUSH: u8 = 0xA;
+const GLOBAL_POP: u8 = 0xB;
+
+// Local item tags.
+const LOCAL_USAGE: u8 = 0x0;
+
+/// Parsed USB HID report descriptor.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ReportDescriptor {
+    /// Top level collections, which each describe a separate function of the
+    /// device (e.g. a mouse, or a vendor configuration interface).
+    pub collections: Vec<Collection>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Collection {
+    /// Collection type, e.g. 1 for application collections.
+    pub kind: u8,
+
+    pub usage_page: u16,
+    pub usage: u16,
+
+    /// Reports with data in this collection or any of its children.
+    pub reports: Vec<Report>,
+
+    pub children: Vec<Collection>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ReportKind {
+    Input,
+    Output,
+    Feature,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Report {
+    /// Report ID, or 0 if the descriptor doesn't use report IDs.
+    pub id: u8,
+
+    pub kind: ReportKind,
+
+    /// Size of the report data in bits, excluding the report ID.
+    pub bits: usize,
+}
+
+impl Report {
+    /// Size of the report data in bytes, excluding the report ID.
+    pub fn len(&self) -> usize {
+        self.bits.div_ceil(8)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+}
+
+impl Collection {
+    pub fn is_vendor_defined(&self) -> bool {
+        self.usage_page >= VENDOR_USAGE_PAGE_MIN
+    }
+
+    /// Returns a report in this collection by ID and kind.
+    pub fn report(&self, id: u8, kind: ReportKind) -> Option<&Report> {
+        self.reports.iter().find(|r| r.id == id && r.kind == kind)
+    }
+}
+
+/// Item state that is saved and restored by push and pop items.
+#[derive(Debug, Clone, Copy, Default)]
+struct GlobalState {
+    usage_page: u16,
+    report_size: usize,
+    report_count: usize,
+    report_id: u8,
+}
+
+/// Reads and parses the report descriptor of a device.
+pub fn read(device: &HidDevice) -> crate::Result<ReportDescriptor> {
+    let mut buf = [0; hidapi::MAX_REPORT_DESCRIPTOR_SIZE];
+    let len = device.get_report_descriptor(&mut buf)?;
+    parse(&buf[..len])
+}
+
+/// Parses a raw report descriptor.
+pub fn parse(descriptor: &[u8]) -> crate::Result<ReportDescriptor> {
+    let malformed = |message: String| crate::Error::MalformedDescriptor(message);
+
+    let mut global = GlobalState::default();
+    let mut global_stack: Vec<GlobalState> = Vec::new();
+    let mut usages: Vec<(u16, u16)> = Vec::new();
+    // Collections that are still open, innermost last.
+    let mut open: Vec<Collection> = Vec::new();
+    let mut collections = Vec::new();
+
+    let mut i = 0;
+    while i < descriptor.len() {
+        let prefix = descriptor[i];
+        if prefix == LONG_ITEM_PREFIX {
+            // Data size, long item tag, then data. No long items are defined.
+            let size = *descriptor
+                .get(i + 1)
+                .ok_or_else(|| malformed(format!("Long item at byte {i} is truncated")))?;
+            i += 3 + size as usize;
+            if i > descriptor.len() {
+                return Err(malformed("Last item is truncated".to_string()));
+            }
+            continue;
+        }
+
+        let size = match prefix & 0b11 {
+            3 => 4,
+            size => size as usize,
+        };
+        let data = descriptor
+            .get((i + 1)..(i + 1 + size))
+            .ok_or_else(|| malformed(format!("Item at byte {i} is truncated")))?;
+        let value = data
+            .iter()
+            .rev()
+            .fold(0u32, |value, byte| (value << 8) | *byte as u32);
+        let item_type = (prefix >> 2) & 0b11;
+        let tag = prefix >> 4;
+
+        match (item_type, tag) {
+            (ITEM_MAIN, MAIN_INPUT | MAIN_OUTPUT | MAIN_FEATURE) => {
+                let kind = match tag {
+                    MAIN_INPUT => ReportKind::Input,
+                    MAIN_OUTPUT => ReportKind::Output,
+                    _ => ReportKind::Feature,
+                };
+                let bits = global.report_size * global.report_count;
+                for collection in &mut open {
+                    add_report_bits(collection, global.report_id, kind, bits);
+                }
+                usages.clear();
+            }
+            (ITEM_MAIN, MAIN_COLLECTION) => {
+                let (usage_page, usage) = usages.first().copied().unwrap_or((global.usage_page, 0));
+                open.push(Collection {
+                    kind: value as u8,
+                    usage_page,
+                    usage,
+                    reports: Vec::new(),
+                    children: Vec::new(),
+                });
+                usages.clear();
+            }
+            (ITEM_MAIN, MAIN_END_COLLECTION) => {
+                let collection = open
+                    .pop()
+                    .ok_or_else(|| malformed(format!("Unopened collection ended at byte {i}")))?;
+                match open.last_mut() {
+                    Some(parent) => parent.children.push(collection),
+                    None => collections.push(collection),
+                }
+                usages.clear();
+            }
+            (ITEM_GLOBAL, GLOBAL_USAGE_PAGE) => global.usage_page = value as u16,
+            (ITEM_GLOBAL, GLOBAL_REPORT_SIZE) => global.report_size = value as usize,
+            (ITEM_GLOBAL, GLOBAL_REPORT_ID) => global.report_id = value as u8,
+            (ITEM_GLOBAL, GLOBAL_REPORT_COUNT) => global.report_count = value as usize,
+            (ITEM_GLOBAL, GLOBAL_PUSH) => global_stack.push(global),
+            (ITEM_GLOBAL, GLOBAL_POP) => {
+                global = global_stack
+                    .pop()
+                    .ok_or_else(|| malformed(format!("Pop without push at byte {i}")))?;
+            }
+            (ITEM_LOCAL, LOCAL_USAGE) => {
+                // 4 byte usages include their own usage page.
+                if size == 4 {
+                    usages.push(((value >> 16) as u16, value as u16));
+                } else {
+                    usages.push((global.usage_page, value as u16));
+                }
+            }
+            _ => {}
+        }
+        i += 1 + size;
+    }
+
+    if !open.is_empty() {
+        return Err(malformed(format!("{} collection(s) not ended", open.len())));
+    }
+
+    Ok(ReportDescriptor { collections })
+}
+
+fn add_report_bits(collection: &mut Collection, id: u8, kind: ReportKind, bits: usize) {
+    match collection
+        .reports
+        .iter_mut()
+        .find(|r| r.id == id && r.kind == kind)
+    {
+        Some(report) => report.bits += bits,
+        None => collection.reports.push(Report { id, kind, bits }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::has_config_report;
+
+    // Descriptors built by hand to the layout of the Atlantis interfaces: a
+    // mouse, consumer control (and keyboard on the receiver) and a vendor
+    // defined collection with the 16 byte configuration report 8. They aren't
+    // dumps from real devices, which should replace them once available.
+
+    /// Mouse collection with report 1.
+    #[rustfmt::skip]
+    const MOUSE: &[u8] = &[
+        0x05, 0x01, 0x09, 0x02, 0xA1, 0x01, 0x85, 0x01, // Mouse, report 1
+        0x09, 0x01, 0xA1, 0x00, // Pointer
+        0x05, 0x09, 0x19, 0x01, 0x29, 0x05, 0x15, 0x00, 0x25, 0x01,
+        0x75, 0x01, 0x95, 0x05, 0x81, 0x02, // 5 buttons
+        0x75, 0x03, 0x95, 0x01, 0x81, 0x01, // Padding
+        0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x16, 0x00, 0x80, 0x26, 0xFF, 0x7F,
+        0x75, 0x10, 0x95, 0x02, 0x81, 0x06, // X, Y
+        0x09, 0x38, 0x15, 0x81, 0x25, 0x7F,
+        0x75, 0x08, 0x95, 0x01, 0x81, 0x06, // Wheel
+        0xC0, 0xC0,
+    ];
+
+    /// Consumer control collection with report 3.
+    const CONSUMER: &[u8] = &[
+        0x05, 0x0C, 0x09, 0x01, 0xA1, 0x01, 0x85, 0x03, 0x19, 0x00, 0x2A, 0xFF, 0x03, 0x15, 0x00,
+        0x26, 0xFF, 0x03, 0x75, 0x10, 0x95, 0x01, 0x81, 0x00, 0xC0,
+    ];
+
+    /// Keyboard collection with report 2.
+    const KEYBOARD: &[u8] = &[
+        0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, 0x85, 0x02, 0x05, 0x07, 0x19, 0xE0, 0x29, 0xE7, 0x15,
+        0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x19, 0x00, 0x29, 0x65, 0x25, 0x65,
+        0x75, 0x08, 0x95, 0x06, 0x81, 0x00, 0xC0,
+    ];
+
+    /// Vendor defined collection with 16 byte input and output reports 8.
+    const CONFIG: &[u8] = &[
+        0x06, 0x02, 0xFF, 0x09, 0x02, 0xA1, 0x01, 0x85, 0x08, 0x09, 0x03, 0x15, 0x00, 0x26, 0xFF,
+        0x00, 0x75, 0x08, 0x95, 0x10, 0x81, 0x02, 0x09, 0x04, 0x91, 0x02, 0xC0,
+    ];
+
+    fn descriptor(parts: &[&[u8]]) -> Vec<u8> {
+        parts.concat()
+    }
+
+    fn vendor_collection(descriptor: &ReportDescriptor) -> &Collection {
+        descriptor
+            .collections
+            .iter()
+            .find(|c| c.is_vendor_defined())
+            .unwrap()
+    }
+
+    #[test]
+    fn wired_descriptor_has_config_report() {
+        let parsed = parse(&descriptor(&[MOUSE, CONSUMER, CONFIG])).unwrap();
+        assert_eq!(parsed.collections.len(), 3);
+        let mouse = &parsed.collections[0];
+        assert_eq!((mouse.usage_page, mouse.usage), (0x01, 0x02));
+        assert_eq!(mouse.children.len(), 1);
+        assert_eq!(mouse.report(1, ReportKind::Input).unwrap().bits, 48);
+
+        let config = vendor_collection(&parsed);
+        assert_eq!((config.usage_page, config.usage), (0xFF02, 0x02));
+        assert_eq!(config.report(8, ReportKind::Output).unwrap().len(), 16);
+        assert!(has_config_report(&parsed));
+    }
+
+    #[test]
+    fn receiver_descriptor_has_config_report() {
+        let parsed = parse(&descriptor(&[MOUSE, KEYBOARD, CONSUMER, CONFIG])).unwrap();
+        assert_eq!(parsed.collections.len(), 4);
+        assert!(has_config_report(&parsed));
+    }
+
+    #[test]
+    fn feature_config_report_is_accepted() {
+        let mut config = CONFIG.to_vec();
+        // Output (0x91) to Feature (0xB1).
+        let output = config.iter().rposition(|&b| b == 0x91).unwrap();
+        config[output] = 0xB1;
+        let parsed = parse(&config).unwrap();
+        assert!(vendor_collection(&parsed)
+            .report(8, ReportKind::Feature)
+            .is_some());
+        assert!(has_config_report(&parsed));
+    }
+
+    #[test]
+    fn other_interfaces_have_no_config_report() {
+        let parsed = parse(&descriptor(&[MOUSE, CONSUMER])).unwrap();
+        assert!(!has_config_report(&parsed));
+
+        // Report 8 in a collection that isn't vendor defined.
+        let mut config = CONFIG.to_vec();
+        config[..3].copy_from_slice(&[0x05, 0x01, 0x00]);
+        assert!(!has_config_report(&parse(&config).unwrap()));
+    }
+
+    #[test]
+    fn truncated_descriptors_are_rejected() {
+        let full = descriptor(&[MOUSE, CONFIG]);
+        // Cut in the middle of the 2 byte logical maximum.
+        let cut = full.len() - 13;
+        assert_eq!(full[cut - 1], 0x26);
+        assert!(matches!(
+            parse(&full[..cut]),
+            Err(crate::Error::MalformedDescriptor(_))
+        ));
+        // Cut before the end of the last collection.
+        assert!(matches!(
+            parse(&full[..(full.len() - 1)]),
+            Err(crate::Error::MalformedDescriptor(_))
+        ));
+        assert!(parse(&[0xC0]).is_err());
+    }
+
+    #[test]
+    fn long_items_are_skipped() {
+        let long_item = [LONG_ITEM_PREFIX, 2, 0xF0, 0xAA, 0xBB];
+        let parsed = parse(&descriptor(&[MOUSE, &long_item, CONFIG])).unwrap();
+        assert!(has_config_report(&parsed));
+
+        assert!(parse(&[LONG_ITEM_PREFIX]).is_err());
+        assert!(parse(&[LONG_ITEM_PREFIX, 4, 0xF0, 0xAA]).is_err());
+    }
+
+    #[test]
+    fn push_and_pop_restore_global_state() {
+        let parsed = parse(&[
+            0x06, 0x02, 0xFF, 0x09, 0x01, 0xA1, 0x01, // Vendor collection
+            0x85, 0x08, 0x75, 0x08, 0x95, 0x10, // Report 8, 16 bytes
+            0xA4, // Push
+            0x85, 0x09, 0x75, 0x01, 0x95, 0x03, 0x81, 0x02, // Report 9, 3 bits
+            0xB4, // Pop
+            0x91, 0x02, // Output with the state from before the push
+            0xC0,
+        ])
+        .unwrap();
+        let collection = vendor_collection(&parsed);
+        assert_eq!(collection.report(9, ReportKind::Input).unwrap().bits, 3);
+        assert_eq!(collection.report(8, ReportKind::Output).unwrap().len(), 16);
+        assert!(has_config_report(&parsed));
+
+        assert!(parse(&[0xB4]).is_err());
+    }
+}
@@ -1,53 +1,237 @@
+use crate::atlantis;
+use crate::capabilities::{self, Capabilities};
+use crate::descriptor::{self, ReportDescriptor, ReportKind};
+use crate::{Mouse, RequestPolicy, Transport};
 use hidapi::{HidApi, HidDevice, HidResult};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
-// Currently only the Lamzu Atlantis Mini Pro is supported. The protocol may be
-// similar in other Lamzu mice but needs testing.
-const VENDOR_ID: u16 = 0x3554;
+pub(crate) const VENDOR_ID: u16 = 0x3554;
 const REPORT_ID: u8 = 8;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+/// Length of the configuration report, excluding the report ID.
+const REPORT_DATA_LEN: usize = 16;
+
+// USB product IDs of the Atlantis Mini Pro and its receivers. The receivers are
+// sold with other models too, so they may not identify the mouse.
+const PID_ATLANTIS_WIRED: u16 = 0xf50f;
+const PID_RECEIVER_1K: u16 = 0xf50d;
+const PID_RECEIVER_4K: u16 = 0xf510;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Product {
     AtlantisWired,
     AtlantisWireless1K,
+    #[default]
     AtlantisWireless4K,
     Unknown,
 }
 
+/// How a product is connected to the computer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum Connection {
+    Wired,
+
+    /// Through a wireless receiver.
+    Wireless,
+}
+
+/// Report descriptor contents that identify the configuration interface.
+#[derive(Debug, Clone, Copy)]
+struct Fingerprint {
+    /// ID of a vendor defined output or feature report.
+    report_id: u8,
+
+    /// Length of the report, excluding the report ID.
+    report_len: usize,
+}
+
+impl Fingerprint {
+    fn matches(&self, descriptor: &ReportDescriptor) -> bool {
+        descriptor.collections.iter().any(|collection| {
+            collection.is_vendor_defined()
+                && [ReportKind::Output, ReportKind::Feature]
+                    .iter()
+                    .any(|kind| {
+                        collection
+                            .report(self.report_id, *kind)
+                            .is_some_and(|report| report.len() == self.report_len)
+                    })
+        })
+    }
+}
+
+/// Configuration report shared by all known products.
+const CONFIG_REPORT: Fingerprint = Fingerprint {
+    report_id: REPORT_ID,
+    report_len: REPORT_DATA_LEN,
+};
+
+/// How a product is identified, and what it supports.
+struct ProductEntry {
+    product: Product,
+    name: &'static str,
+
+    /// USB product ID, or `None` if it isn't known.
+    product_id: Option<u16>,
+
+    /// Text that the USB product string must contain, ignoring case. Used for
+    /// products whose product ID is shared or unknown.
+    usb_product: Option<&'static str>,
+
+    fingerprint: Fingerprint,
+    receiver: bool,
+
+    /// Whether the product has been tested with this tool.
+    tested: bool,
+
+    capabilities: Capabilities,
+    driver: Driver,
+}
+
+/// Opens a driver for an identified device over any transport.
+type Driver = fn(Box<dyn Transport>, Product, RequestPolicy) -> Box<dyn Mouse>;
+
+/// Known products, in the order they are matched.
+///
+/// Other models are only added once their product IDs and limits are known.
+/// Until then, models that share the Atlantis receivers (e.g. the Thorn) are
+/// identified as an Atlantis. Entries matching by USB product string must come
+/// before those for the same product ID that don't, so they claim shared
+/// receivers first.
+///
+/// Every known product has the same configuration report, so the fingerprint
+/// only finds the configuration interface and doesn't tell models apart.
+const PRODUCTS: [ProductEntry; 3] = [
+    ProductEntry {
+        product: Product::AtlantisWired,
+        name: "Lamzu Atlantis Wired",
+        product_id: Some(PID_ATLANTIS_WIRED),
+        usb_product: None,
+        fingerprint: CONFIG_REPORT,
+        receiver: false,
+        tested: true,
+        capabilities: capabilities::ATLANTIS,
+        driver: atlantis::open_driver,
+    },
+    ProductEntry {
+        product: Product::AtlantisWireless1K,
+        name: "Lamzu Atlantis Wireless (1K)",
+        product_id: Some(PID_RECEIVER_1K),
+        usb_product: None,
+        fingerprint: CONFIG_REPORT,
+        receiver: true,
+        tested: true,
+        capabilities: capabilities::ATLANTIS,
+        driver: atlantis::open_driver,
+    },
+    ProductEntry {
+        product: Product::AtlantisWireless4K,
+        name: "Lamzu Atlantis Wireless (4K)",
+        product_id: Some(PID_RECEIVER_4K),
+        usb_product: None,
+        fingerprint: CONFIG_REPORT,
+        receiver: true,
+        tested: true,
+        capabilities: capabilities::ATLANTIS_4K,
+        driver: atlantis::open_driver,
+    },
+];
+
+impl ProductEntry {
+    fn matches(
+        &self,
+        product_id: u16,
+        usb_product: Option<&str>,
+        descriptor: &ReportDescriptor,
+    ) -> bool {
+        self.product_id.is_none_or(|id| id == product_id)
+            && self
+                .usb_product
+                .is_none_or(|name| usb_product.is_some_and(|s| s.to_lowercase().contains(name)))
+            && self.fingerprint.matches(descriptor)
+    }
+}
+
 impl Product {
+    /// Identifies a product by USB product ID alone, which can't tell apart
+    /// models that share a receiver.
     pub fn from_usb_product(product_id: u16) -> Product {
-        match product_id {
-            0xf50f => Self::AtlantisWired,
-            0xf50d => Self::AtlantisWireless1K,
-            0xf510 => Self::AtlantisWireless4K,
-            _ => Self::Unknown,
+        PRODUCTS
+            .iter()
+            .find(|entry| entry.usb_product.is_none() && entry.product_id == Some(product_id))
+            .map_or(Self::Unknown, |entry| entry.product)
+    }
+
+    /// Identifies a product from its USB product ID, USB product string and
+    /// report descriptor. Returns `Unknown` for devices that have the
+    /// configuration report but aren't a known product, and `None` for devices
+    /// without it.
+    pub fn identify(
+        product_id: u16,
+        usb_product: Option<&str>,
+        descriptor: &ReportDescriptor,
+    ) -> Option<Product> {
+        match PRODUCTS
+            .iter()
+            .find(|entry| entry.matches(product_id, usb_product, descriptor))
+        {
+            Some(entry) => Some(entry.product),
+            None if has_config_report(descriptor) => Some(Self::Unknown),
+            None => None,
         }
     }
 
-    pub fn max_poll_rate(&self) -> u16 {
-        match self {
-            Self::AtlantisWired => 1000,
-            Self::AtlantisWireless1K => 1000,
-            Self::AtlantisWireless4K => 4000,
-            Self::Unknown => 1000,
+    fn entry(&self) -> Option<&'static ProductEntry> {
+        PRODUCTS.iter().find(|entry| entry.product == *self)
+    }
+
+    /// Whether the product is a wireless receiver rather than the mouse itself.
+    pub fn is_receiver(&self) -> bool {
+        self.entry().is_some_and(|entry| entry.receiver)
+    }
+
+    pub fn connection(&self) -> Connection {
+        if self.is_receiver() {
+            Connection::Wireless
+        } else {
+            Connection::Wired
         }
     }
-}
 
-impl Default for Product {
-    fn default() -> Self {
-        Self::AtlantisWireless4K
+    /// Whether the product has been tested with this tool. Untested products
+    /// may still work, but could be damaged by settings they don't support.
+    pub fn is_tested(&self) -> bool {
+        self.entry().is_some_and(|entry| entry.tested)
+    }
+
+    /// Returns the settings and limits supported by the product.
+    pub fn capabilities(&self) -> Capabilities {
+        self.entry()
+            .map_or(capabilities::ATLANTIS, |entry| entry.capabilities)
+    }
+
+    /// Opens the product's driver for an already identified device, over any
+    /// transport (e.g. a [`HidDevice`] wrapped in a
+    /// [`Trace`](crate::atlantis::Trace)). Unknown products use the Atlantis
+    /// driver.
+    pub fn open(
+        self,
+        transport: impl Transport + 'static,
+        policy: RequestPolicy,
+    ) -> Box<dyn Mouse> {
+        let driver = self
+            .entry()
+            .map_or(atlantis::open_driver as Driver, |entry| entry.driver);
+        driver(Box::new(transport), self, policy)
     }
 }
 
 impl fmt::Display for Product {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Self::AtlantisWired => write!(f, "Lamzu Atlantis Wired"),
-            Self::AtlantisWireless1K => write!(f, "Lamzu Atlantis Wireless (1K)"),
-            Self::AtlantisWireless4K => write!(f, "Lamzu Atlantis Wireless (4K)"),
-            Self::Unknown => write!(f, "Unknown Device"),
+        match self.entry() {
+            Some(entry) => write!(f, "{}", entry.name),
+            None => write!(f, "Unknown Device"),
         }
     }
 }
@@ -64,20 +248,27 @@ pub fn devices_by_pid(pid: u16) -> HidResult<Vec<(HidDevice, Product)>> {
 }
 
 fn get_devices(filter_pid: Option<u16>) -> HidResult<Vec<(HidDevice, Product)>> {
-    let mut api = HidApi::new()?;
+    find_devices(&mut HidApi::new()?, filter_pid)
+}
+
+/// Lists potentially compatible devices known to `api`, after refreshing them.
+pub(crate) fn find_devices(
+    api: &mut HidApi,
+    filter_pid: Option<u16>,
+) -> HidResult<Vec<(HidDevice, Product)>> {
     api.reset_devices()?;
     api.add_devices(VENDOR_ID, filter_pid.unwrap_or(0))?;
     let mut device_infos: Vec<_> = api.device_list().collect();
 
     // Deduplicate based on hidraw path.
-    device_infos.sort_by(|a, b| a.path().partial_cmp(b.path()).unwrap());
+    device_infos.sort_by(|a, b| a.path().cmp(b.path()));
     device_infos.dedup_by(|a, b| a.path() == b.path());
 
     let mut devices: Vec<_> = device_infos
         .iter()
         .filter_map(|info| {
             let device = info
-                .open_device(&api)
+                .open_device(api)
                 .inspect_err(|e| eprintln!("USB HID error: {e}"))
                 .ok()?;
             let id = identify(&device)
@@ -88,60 +279,27 @@ fn get_devices(filter_pid: Option<u16>) -> HidResult<Vec<(HidDevice, Product)>>
         .collect();
 
     // Sort by connection priority.
-    devices.sort_by(|(_, product_a), (_, product_b)| product_a.cmp(&product_b));
+    devices.sort_by_key(|(_, product)| *product);
 
     Ok(devices)
 }
 
 /// Attempt to identify the connected device, returning `None` for devices that
 /// are incompatible.
-pub fn identify(device: &HidDevice) -> HidResult<Option<Product>> {
+pub fn identify(device: &HidDevice) -> crate::Result<Option<Product>> {
     let device_info = device.get_device_info()?;
-    if device_info.vendor_id() == VENDOR_ID {
-        let mut report_descriptor = [0; hidapi::MAX_REPORT_DESCRIPTOR_SIZE];
-        let desc_len = device.get_report_descriptor(&mut report_descriptor)?;
-        if has_report(&report_descriptor[..desc_len], REPORT_ID) {
-            return Ok(Some(Product::from_usb_product(device_info.product_id())));
-        }
+    if device_info.vendor_id() != VENDOR_ID {
+        return Ok(None);
     }
-    Ok(None)
+    Ok(Product::identify(
+        device_info.product_id(),
+        device_info.product_string(),
+        &descriptor::read(device)?,
+    ))
 }
 
-/// Tests whether `report_descriptor` contains `report_id`.
-///
-/// Implements a basic USB HID report descriptor parser that skips any items
-/// that are not report ID items. Returns `true` if any report ID item matches
-/// `report_id`.
-fn has_report(report_descriptor: &[u8], report_id: u8) -> bool {
-    let mut i = 0;
-    while i < report_descriptor.len() {
-        let prefix = report_descriptor[i];
-        i += 1;
-
-        // Long item
-        if prefix == 0b1111_1110 {
-            unimplemented!("Long report descriptor item parsing is unimplemented");
-        } else {
-            // 1 byte report ID item
-            if prefix == 0b1000_0101 {
-                if report_descriptor[i] == report_id {
-                    return true;
-                }
-                i += 1;
-            } else {
-                let data_len = match prefix & 0b11 {
-                    0 => 0,
-                    1 => 1,
-                    2 => 2,
-                    3 => 4,
-                    _ => unreachable!(),
-                };
-
-                // Skip item
-                i += data_len;
-            }
-        }
-    }
-
-    false
+/// Tests whether a report descriptor has the configuration report, which is a
+/// vendor defined output or feature report.
+pub fn has_config_report(descriptor: &ReportDescriptor) -> bool {
+    CONFIG_REPORT.matches(descriptor)
 }
@@ -15,6 +15,33 @@ pub enum Error {
     #[error("Device is not compatible")]
     Incompatible,
 
-    #[error("No valid response for request")]
-    NoResponse,
+    #[error("Timed out waiting for a response from the mouse")]
+    Timeout,
+
+    #[error("Mouse is not responding. It may be asleep; move it to wake it up")]
+    DeviceAsleep,
+
+    #[error("Report has a bad checksum: {0:02X?}")]
+    BadChecksum(Vec<u8>),
+
+    #[error("Report is malformed: {0:02X?}")]
+    MalformedReport(Vec<u8>),
+
+    #[error("Short read from device ({actual} of {expected} bytes)")]
+    ShortRead { expected: usize, actual: usize },
+
+    #[error("Short write to device ({actual} of {expected} bytes)")]
+    ShortWrite { expected: usize, actual: usize },
+
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+
+    #[error("Capture file is malformed: {0}")]
+    MalformedCapture(String),
+
+    #[error("Exchange differs from the capture: expected {expected}, got {actual}")]
+    ReplayMismatch { expected: String, actual: String },
+
+    #[error("USB HID report descriptor is malformed: {0}")]
+    MalformedDescriptor(String),
 }
@@ -0,0 +1,233 @@
+use crate::device::VENDOR_ID;
+use hidapi::HidApi;
+use std::collections::BTreeSet;
+use std::ffi::CString;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Waits for Lamzu HID devices to be connected or disconnected.
+///
+/// On Linux, udev events are received over netlink. Elsewhere, or if the
+/// netlink socket can't be opened, the connected devices are polled instead.
+/// Either way the monitor only tells when something may have changed, so the
+/// devices have to be listed again to find out what.
+pub struct Monitor {
+    source: Source,
+}
+
+enum Source {
+    #[cfg(target_os = "linux")]
+    Udev(netlink::UeventSocket),
+    Poll(Poller),
+}
+
+impl Monitor {
+    /// Uses udev if available, or else polls every `poll_interval`.
+    pub fn new(poll_interval: Duration) -> crate::Result<Self> {
+        #[cfg(target_os = "linux")]
+        if let Ok(socket) = netlink::UeventSocket::new() {
+            return Ok(Self {
+                source: Source::Udev(socket),
+            });
+        }
+        Self::polling(poll_interval)
+    }
+
+    /// Polls the connected devices every `interval`, e.g. on systems where
+    /// udev isn't running.
+    pub fn polling(interval: Duration) -> crate::Result<Self> {
+        Ok(Self {
+            source: Source::Poll(Poller::new(interval)?),
+        })
+    }
+
+    /// Whether changes are detected by polling rather than udev events.
+    pub fn is_polling(&self) -> bool {
+        matches!(self.source, Source::Poll(_))
+    }
+
+    /// Blocks until a device may have been connected or disconnected.
+    pub fn wait(&mut self) -> crate::Result<()> {
+        self.wait_until(None).map(|_| ())
+    }
+
+    /// Blocks until a device may have been connected or disconnected, or the
+    /// timeout has passed. Returns whether anything may have changed.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> crate::Result<bool> {
+        self.wait_until(Some(Instant::now() + timeout))
+    }
+
+    fn wait_until(&mut self, deadline: Option<Instant>) -> crate::Result<bool> {
+        match &mut self.source {
+            #[cfg(target_os = "linux")]
+            Source::Udev(socket) => Ok(socket.wait(deadline)?),
+            Source::Poll(poller) => poller.wait(deadline),
+        }
+    }
+}
+
+struct Poller {
+    api: HidApi,
+    interval: Duration,
+    paths: BTreeSet<CString>,
+}
+
+impl Poller {
+    fn new(interval: Duration) -> crate::Result<Self> {
+        let mut poller = Self {
+            api: HidApi::new()?,
+            interval,
+            paths: BTreeSet::new(),
+        };
+        poller.paths = poller.paths()?;
+        Ok(poller)
+    }
+
+    /// Lists the HID paths of all Lamzu devices.
+    fn paths(&mut self) -> crate::Result<BTreeSet<CString>> {
+        self.api.refresh_devices()?;
+        Ok(self
+            .api
+            .device_list()
+            .filter(|info| info.vendor_id() == VENDOR_ID)
+            .map(|info| info.path().to_owned())
+            .collect())
+    }
+
+    fn wait(&mut self, deadline: Option<Instant>) -> crate::Result<bool> {
+        loop {
+            let mut sleep = self.interval;
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Ok(false);
+                }
+                sleep = sleep.min(remaining);
+            }
+            thread::sleep(sleep);
+            let paths = self.paths()?;
+            if paths != self.paths {
+                self.paths = paths;
+                return Ok(true);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod netlink {
+    use std::io;
+    use std::mem;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::time::Instant;
+
+    /// Multicast group of events sent by udev after its rules have run, so
+    /// device permissions are already set when they arrive.
+    const UDEV_GROUP: u32 = 2;
+
+    const HIDRAW_PROPERTY: &[u8] = b"SUBSYSTEM=hidraw\0";
+
+    pub struct UeventSocket {
+        fd: OwnedFd,
+    }
+
+    impl UeventSocket {
+        pub fn new() -> io::Result<Self> {
+            // SAFETY: Plain socket creation. The descriptor is owned right away.
+            let fd = unsafe {
+                libc::socket(
+                    libc::AF_NETLINK,
+                    libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                    libc::NETLINK_KOBJECT_UEVENT,
+                )
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: `fd` is a newly created descriptor that nothing else owns.
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+            // SAFETY: All zeroes is a valid `sockaddr_nl`.
+            let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+            address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            address.nl_groups = UDEV_GROUP;
+            // SAFETY: `address` is a valid `sockaddr_nl` of the given size.
+            let result = unsafe {
+                libc::bind(
+                    fd.as_raw_fd(),
+                    (&address as *const libc::sockaddr_nl).cast(),
+                    mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+                )
+            };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { fd })
+        }
+
+        /// Blocks until a hidraw device is added or removed, or the deadline
+        /// has passed. Returns whether a device may have changed.
+        ///
+        /// Events are only a hint to list the devices again, so they aren't
+        /// parsed any further and their sender isn't checked.
+        pub fn wait(&self, deadline: Option<Instant>) -> io::Result<bool> {
+            let mut buf = [0u8; 8192];
+            loop {
+                if let Some(deadline) = deadline {
+                    if !self.poll(deadline)? {
+                        return Ok(false);
+                    }
+                }
+                // SAFETY: `buf` is valid for writes of its whole length.
+                let len = unsafe {
+                    libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0)
+                };
+                if len < 0 {
+                    let error = io::Error::last_os_error();
+                    match error.raw_os_error() {
+                        Some(libc::EINTR) => continue,
+                        // Events were dropped, so one of them could have been ours.
+                        Some(libc::ENOBUFS) => return Ok(true),
+                        _ => return Err(error),
+                    }
+                }
+                let event = &buf[..len as usize];
+                if event
+                    .windows(HIDRAW_PROPERTY.len())
+                    .any(|window| window == HIDRAW_PROPERTY)
+                {
+                    return Ok(true);
+                }
+            }
+        }
+
+        /// Blocks until an event can be received, or the deadline has passed.
+        /// Returns whether there is an event.
+        fn poll(&self, deadline: Instant) -> io::Result<bool> {
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                // Round up so a deadline that's nearly reached isn't polled for
+                // repeatedly without waiting.
+                let timeout = remaining
+                    .as_nanos()
+                    .div_ceil(1_000_000)
+                    .min(libc::c_int::MAX as u128) as libc::c_int;
+                let mut pollfd = libc::pollfd {
+                    fd: self.fd.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                // SAFETY: `pollfd` is a single valid `pollfd`.
+                let result = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+                if result < 0 {
+                    let error = io::Error::last_os_error();
+                    if error.raw_os_error() == Some(libc::EINTR) {
+                        continue;
+                    }
+                    return Err(error);
+                }
+                return Ok(result > 0);
+            }
+        }
+    }
+}
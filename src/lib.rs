@@ -1,38 +1,174 @@
-mod atlantis;
-pub use atlantis::Atlantis;
+pub mod atlantis;
+pub mod battery;
+mod capabilities;
+pub use capabilities::Capabilities;
+pub mod descriptor;
+pub use atlantis::{Atlantis, Emulator, RequestPolicy};
 mod device;
-pub use device::{devices, devices_by_pid, identify, Product};
+pub use device::{devices, devices_by_pid, has_config_report, identify, Connection, Product};
 mod error;
 pub use error::Error;
+pub mod hotplug;
+mod manager;
+pub use manager::{open, DeviceManager};
 pub mod profile;
 pub use profile::Profile;
+mod transport;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+pub use transport::{Transport, UsbInfo};
 
 pub type Result<T> = std::result::Result<T, error::Error>;
 
+/// Amount of data written to a device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteSummary {
+    /// Number of flash bytes written.
+    pub bytes: usize,
+
+    /// Number of write reports sent.
+    pub reports: usize,
+}
+
+/// Information about a connected mouse and its firmware.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DeviceInfo {
+    pub manufacturer: Option<String>,
+    pub product_name: Option<String>,
+    pub serial_number: Option<String>,
+
+    /// USB device release number, which is the firmware version of the mouse
+    /// or receiver that is plugged in.
+    pub usb_release: Option<String>,
+
+    /// Firmware version of the mouse, if known.
+    pub firmware_version: Option<String>,
+
+    /// Firmware version of the wireless receiver, if connected through one.
+    pub receiver_firmware_version: Option<String>,
+}
+
+/// What identifies the physical mouse behind a connection, for telling whether
+/// two connections (e.g. a cable and a wireless receiver) lead to the same
+/// mouse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MouseIdentity {
+    pub connection: Connection,
+
+    /// USB serial number of the device, if it has one.
+    pub serial_number: Option<String>,
+
+    /// Snapshot of the mouse's current settings in a driver specific format,
+    /// compared when a serial number is missing. Only required if the device
+    /// has no serial number, so a sleeping mouse can still be matched by it.
+    pub settings: Option<Vec<u8>>,
+}
+
+impl MouseIdentity {
+    /// Tests whether both identities belong to the same mouse connected in
+    /// different ways.
+    ///
+    /// There is no known command for a unique ID, so connections are matched by
+    /// USB serial number. Only if either of them has no serial number are they
+    /// matched by their settings, in which case two mice with identical
+    /// settings can be mistaken for one. Either way this is only a guess.
+    pub fn is_same_mouse(&self, other: &MouseIdentity) -> bool {
+        if self.connection == other.connection {
+            return false;
+        }
+        match (&self.serial_number, &other.serial_number) {
+            (Some(serial_number), Some(other_serial_number)) => {
+                serial_number == other_serial_number
+            }
+            _ => self.settings.is_some() && self.settings == other.settings,
+        }
+    }
+}
+
+/// Battery state of a wireless mouse.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BatteryStatus {
+    pub millivolts: u16,
+
+    /// Rough charge percentage estimated from the voltage.
+    pub percentage: u8,
+
+    /// Experimental, see [`ChargeState`].
+    pub charge_state: ChargeState,
+}
+
+/// Whether a battery is being charged.
+///
+/// This is experimental. Which raw values mean charging or full is a guess that
+/// hasn't been confirmed with a capture, so they may be reported wrongly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChargeState {
+    Discharging,
+    Charging,
+    Full,
+
+    /// Raw state that isn't understood yet.
+    Unknown(u8),
+}
+
 /// Trait for supported mice that can be configured via profiles.
+///
+/// The trait is dyn compatible, so drivers can be used as `Box<dyn Mouse>`
+/// (see [`open`]).
 pub trait Mouse {
-    const NUM_PROFILES: usize;
+    /// Returns the product that this driver was opened for.
+    fn product(&self) -> Product;
+
+    /// Returns the driver itself, so features specific to its protocol can be
+    /// used by downcasting it (e.g. to an [`Atlantis`]).
+    fn as_any(&self) -> &dyn Any
+    where
+        Self: 'static;
+
+    /// Returns the number of profiles stored on the device.
+    fn num_profiles(&self) -> usize;
+
+    /// Returns the settings and limits supported by the device.
+    fn capabilities(&self) -> Capabilities {
+        self.product().capabilities()
+    }
 
     /// Returns a specific profile from the device.
     fn profile(&self, index: usize) -> crate::Result<Profile>;
 
-    /// Write to a specific profile on the device.
-    fn set_profile(&self, index: usize, profile: &Profile) -> crate::Result<()>;
+    /// Write to a specific profile on the device, returning how much was
+    /// actually written.
+    fn set_profile(&self, index: usize, profile: &Profile) -> crate::Result<WriteSummary>;
 
     /// Returns all profiles from the device.
     fn profiles(&self) -> crate::Result<Vec<Profile>> {
-        (0..Self::NUM_PROFILES)
-            .into_iter()
-            .map(|i| self.profile(i))
-            .collect()
+        (0..self.num_profiles()).map(|i| self.profile(i)).collect()
     }
 
     /// Write multiple profiles to the device.
-    fn set_profiles(&self, profiles: &[Profile]) -> crate::Result<()> {
+    fn set_profiles(&self, profiles: &[Profile]) -> crate::Result<WriteSummary> {
+        let mut summary = WriteSummary::default();
         for (i, profile) in profiles.iter().enumerate() {
-            self.set_profile(i, profile)?;
+            let profile_summary = self.set_profile(i, profile)?;
+            summary.bytes += profile_summary.bytes;
+            summary.reports += profile_summary.reports;
         }
-        Ok(())
+        Ok(summary)
+    }
+
+    /// Resets a specific profile on the device to its default settings.
+    fn reset_profile(&self, index: usize) -> crate::Result<WriteSummary>;
+
+    /// Resets all profiles to their default settings and activates the first.
+    fn factory_reset(&self) -> crate::Result<WriteSummary> {
+        let mut summary = WriteSummary::default();
+        for i in 0..self.num_profiles() {
+            let profile_summary = self.reset_profile(i)?;
+            summary.bytes += profile_summary.bytes;
+            summary.reports += profile_summary.reports;
+        }
+        self.set_active_profile(0)?;
+        Ok(summary)
     }
 
     /// Returns the index of the currently active profile.
@@ -41,9 +177,27 @@ pub trait Mouse {
     /// Set the active profile by index.
     fn set_active_profile(&self, index: usize) -> crate::Result<()>;
 
+    /// Returns the battery voltage, charge and charging state.
+    fn battery_status(&self) -> crate::Result<BatteryStatus>;
+
     /// Returns the battery voltage in millivolts.
-    fn battery_voltage(&self) -> crate::Result<u16>;
+    fn battery_voltage(&self) -> crate::Result<u16> {
+        Ok(self.battery_status()?.millivolts)
+    }
 
     /// Returns the rough battery charge percentage.
-    fn battery_percentage(&self) -> crate::Result<u8>;
+    fn battery_percentage(&self) -> crate::Result<u8> {
+        Ok(self.battery_status()?.percentage)
+    }
+
+    /// Returns information about the mouse and its firmware.
+    fn device_info(&self) -> crate::Result<DeviceInfo>;
+
+    /// Returns what identifies the physical mouse behind this connection (see
+    /// [`MouseIdentity::is_same_mouse`]).
+    ///
+    /// A mouse that doesn't respond straight away isn't waited for, so that a
+    /// sleeping mouse doesn't hold up listing devices. This only fails if the
+    /// settings are needed because there is no serial number.
+    fn identity(&self) -> crate::Result<MouseIdentity>;
 }
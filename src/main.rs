@@ -1,11 +1,38 @@
-use clap::{Parser, Subcommand, ValueHint};
-use lamzu::{Atlantis, Mouse, Product, Profile};
-use serde::Serialize;
-use std::fs::File;
-use std::io::{self, stdin, Read};
+use clap::{Parser, Subcommand, ValueEnum, ValueHint};
+use hidapi::HidDevice;
+use lamzu::atlantis::{self, Direction, FlashImage, Trace};
+use lamzu::battery::{self, BatterySample};
+use lamzu::descriptor::{self, ReportDescriptor};
+use lamzu::hotplug::Monitor;
+use lamzu::{
+    Atlantis, BatteryStatus, Connection, DeviceInfo, Mouse, MouseIdentity, Product, Profile,
+    RequestPolicy, Transport,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, stdin, BufRead, BufReader, LineWriter, Read, Write};
+use std::ops::Range;
 use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{process, thread};
 
-#[derive(Debug, Parser)]
+/// How long to keep asking a sleeping mouse to wake up before giving up.
+const WAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of battery checks kept in memory for estimating the runtime.
+const MAX_BATTERY_SAMPLES: usize = 1000;
+
+/// How long the daemon waits before setting up a mouse again after failing.
+const DAEMON_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+const PROBE_REQUEST_POLICY: RequestPolicy = RequestPolicy {
+    timeout: Duration::from_millis(250),
+    retries: 0,
+    backoff: Duration::ZERO,
+};
+
+#[derive(Debug, Clone, Parser)]
 #[command(name = "lamzu")]
 #[command(about = "Lamzu mouse configuration tool", long_about = None)]
 struct Cli {
@@ -13,21 +40,74 @@ struct Cli {
     #[arg(short, long)]
     device: Option<String>,
 
+    /// Use the device with this USB serial number
+    #[arg(long)]
+    serial: Option<String>,
+
+    /// Use the device at this HID path (e.g. /dev/hidraw3)
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    path: Option<String>,
+
+    /// Use the device at this index in the output of `list`
+    #[arg(long)]
+    index: Option<usize>,
+
+    /// Connection to use if several devices are selected without `--all`,
+    /// e.g. a mouse connected by both cable and wireless receiver
+    #[arg(long, value_enum, default_value_t = Prefer::Wired)]
+    prefer: Prefer,
+
     /// Force using untested devices
     #[arg(short, long)]
     force: bool,
 
+    /// Time to wait for each response from the mouse in milliseconds
+    #[arg(long)]
+    timeout: Option<u64>,
+
+    /// Number of times to re-send a request that got no response or a corrupt
+    /// one
+    #[arg(long)]
+    retries: Option<u32>,
+
+    /// Log every report exchanged with the mouse to stderr
+    #[arg(short = 'v', long)]
+    trace: bool,
+
+    /// Save every report exchanged with the mouse to a capture file
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    capture: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Command,
 }
 
-#[derive(Debug, Subcommand)]
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Prefer {
+    Wired,
+    Wireless,
+}
+
+impl From<Prefer> for Connection {
+    fn from(prefer: Prefer) -> Self {
+        match prefer {
+            Prefer::Wired => Connection::Wired,
+            Prefer::Wireless => Connection::Wireless,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Subcommand)]
 enum Command {
     /// List compatible devices
     List {
         /// Output in JSON instead of RON
         #[arg(short, long)]
         json: bool,
+
+        /// Also show the configuration interface and its report descriptor
+        #[arg(short, long)]
+        verbose: bool,
     },
 
     /// Read profile(s) from mouse and print
@@ -39,6 +119,10 @@ enum Command {
         /// Read from a specific profile by number
         #[arg(short, long)]
         profile: Option<usize>,
+
+        /// Read from every selected device instead of the first
+        #[arg(long)]
+        all: bool,
     },
 
     /// Write profile(s) to mouse
@@ -58,6 +142,25 @@ enum Command {
         /// Input profile configuration
         #[arg(group = "profile_in")]
         config: Option<String>,
+
+        /// Write to every selected device instead of the first
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// Reset profile(s) on the mouse to default settings
+    Reset {
+        /// Reset a specific profile by number
+        #[arg(short, long, required_unless_present = "all", conflicts_with = "all")]
+        profile: Option<usize>,
+
+        /// Reset all profiles and activate the first
+        #[arg(short, long)]
+        all: bool,
+
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
     },
 
     /// Get the active profile number on the mouse
@@ -67,67 +170,539 @@ enum Command {
     SetActive {
         /// Active profile number to set
         profile_number: usize,
+
+        /// Set the active profile on every selected device instead of the first
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// Get the mouse's firmware version and other device information
+    Info {
+        /// Output in JSON instead of RON
+        #[arg(short, long)]
+        json: bool,
+    },
+
+    /// Show the settings and limits supported by the mouse
+    Capabilities {
+        /// Output in JSON instead of RON
+        #[arg(short, long)]
+        json: bool,
     },
 
     /// Get the battery charge percentage.
+    #[command(visible_alias = "battery")]
     GetBattery {
         /// Return raw millivolts instead of percent.
-        #[arg(short, long)]
+        #[arg(short, long, conflicts_with = "status")]
         millivolts: bool,
+
+        /// Return the full battery status, including whether it's charging
+        /// (experimental).
+        #[arg(short, long)]
+        status: bool,
+
+        /// Output status in JSON instead of RON.
+        #[arg(short, long, requires = "status")]
+        json: bool,
+
+        /// Keep checking the battery at an interval.
+        #[arg(short, long, conflicts_with_all = ["millivolts", "status"])]
+        watch: bool,
+
+        /// Seconds between checks when watching.
+        #[arg(long, default_value_t = 300, requires = "watch")]
+        interval: u64,
+
+        /// Append each check to a history file, which is also used to estimate
+        /// the remaining runtime.
+        #[arg(long, requires = "watch", value_hint = ValueHint::FilePath)]
+        history: Option<PathBuf>,
+
+        /// Percentages at which to run hooks when the charge drops to them.
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "20,10,5",
+            requires = "watch"
+        )]
+        thresholds: Vec<u8>,
+
+        /// Command to run with `sh -c` when a threshold is reached.
+        #[arg(long, requires = "watch")]
+        exec: Option<String>,
+
+        /// Show a desktop notification with `notify-send` when a threshold is
+        /// reached.
+        #[arg(long, requires = "watch")]
+        notify: bool,
+    },
+
+    /// Dump the raw flash of profile(s) to a file for a byte-exact backup
+    DumpFlash {
+        /// Dump a specific profile by number (all profiles if omitted)
+        #[arg(short, long)]
+        profile: Option<usize>,
+
+        /// Output file
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        output: PathBuf,
+    },
+
+    /// Restore the raw flash of profile(s) from a dump
+    RestoreFlash {
+        /// Restore a specific profile by number (all profiles if omitted)
+        #[arg(short, long)]
+        profile: Option<usize>,
+
+        /// Input file from `dump-flash`
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        input: PathBuf,
+    },
+
+    /// Encode a profile into a binary flash image without a mouse
+    Encode {
+        /// Input profile in JSON instead of RON
+        #[arg(short, long)]
+        json: bool,
+
+        /// Input profile configuration from file
+        #[arg(group = "profile_in", short, long, value_hint = ValueHint::FilePath)]
+        file: Option<PathBuf>,
+
+        /// Input profile configuration
+        #[arg(group = "profile_in")]
+        config: Option<String>,
+
+        /// Apply the profile on top of an existing flash image
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        base: Option<PathBuf>,
+
+        /// Product to check the profile against, as shown by `list --json`
+        #[arg(long, default_value = "AtlantisWireless4K", value_parser = parse_product)]
+        product: Product,
+
+        /// Output flash image file
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        output: PathBuf,
+    },
+
+    /// Decode a binary flash image into a profile without a mouse
+    Decode {
+        /// Output profile in JSON instead of RON
+        #[arg(short, long)]
+        json: bool,
+
+        /// Flash image file
+        #[arg(value_hint = ValueHint::FilePath)]
+        image: PathBuf,
+
+        /// Product the image is from, as shown by `list --json`
+        #[arg(long, default_value = "AtlantisWireless4K", value_parser = parse_product)]
+        product: Product,
+    },
+
+    /// Print changes made on the mouse (e.g. profile button presses) as JSON
+    /// lines (experimental)
+    Monitor,
+
+    /// Probe the mouse for unknown commands and unmapped flash contents
+    Probe {
+        /// Output report in JSON instead of RON
+        #[arg(short, long)]
+        json: bool,
+
+        /// Also send odd command bytes, which are more likely to write
+        #[arg(long)]
+        odd_commands: bool,
+
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Decode the mouse's reports in a usbmon or USBPcap capture file
+    DecodeCapture {
+        /// pcap or pcapng capture file
+        #[arg(value_hint = ValueHint::FilePath)]
+        capture: PathBuf,
+    },
+
+    /// Watch for mice being connected and apply configured settings to them
+    Daemon {
+        /// Config in JSON instead of RON
+        #[arg(short, long)]
+        json: bool,
+
+        /// Config file with the settings for each mouse
+        #[arg(value_hint = ValueHint::FilePath)]
+        config: PathBuf,
+
+        /// Poll for devices instead of waiting for udev events
+        #[arg(long)]
+        poll: bool,
+
+        /// Seconds between polls
+        #[arg(long, default_value_t = 2)]
+        interval: u64,
     },
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Cli::parse();
+    let mut args = Cli::parse();
 
-    let devices = if let Some(pid_str) = &args.device {
-        let pid =
-            u16::from_str_radix(pid_str, 16).map_err(|_| "Invalid USB product ID".to_string())?;
-        lamzu::devices_by_pid(pid)?
-    } else {
-        lamzu::devices()?
-    };
+    if let Command::Daemon {
+        json,
+        config,
+        poll,
+        interval,
+    } = &args.command
+    {
+        if args.capture.is_some() {
+            return Err("A capture file can't be saved by the daemon.".into());
+        }
+        let config = fs::read_to_string(config)?;
+        let config: DaemonConfig = if *json {
+            serde_json::from_str(&config)?
+        } else {
+            ron::de::from_str(&config)?
+        };
+        let interval = Duration::from_secs(*interval);
+        let monitor = if *poll {
+            Monitor::polling(interval)?
+        } else {
+            Monitor::new(interval)?
+        };
+        return run_daemon(&args, &config, monitor);
+    }
 
-    if let Command::List { json } = args.command {
-        let list: Vec<_> = devices
-            .iter()
-            .map(|(device, product)| {
-                let info = device.get_device_info().unwrap();
-                ListedDevice {
-                    pid: format!("{:04x}", info.product_id()),
-                    product: product.clone(),
-                }
+    // Offline commands that don't need a mouse.
+    match args.command {
+        Command::Encode {
+            json,
+            file,
+            config,
+            base,
+            product,
+            output,
+        } => {
+            let input = get_file_arg_or_stdin(file, config)?;
+            let profile: Profile = if json {
+                serde_json::from_str(&input)?
+            } else {
+                ron::de::from_str(&input)?
+            };
+            let image = if let Some(base) = base {
+                let mut image = FlashImage::from_bytes(fs::read(base)?)?;
+                atlantis::encode_profile_into(&profile, product, &mut image)?;
+                image
+            } else {
+                atlantis::encode_profile(&profile, product)?
+            };
+            fs::write(output, image.as_bytes())?;
+            return Ok(());
+        }
+
+        Command::Decode {
+            json,
+            image,
+            product,
+        } => {
+            let image = FlashImage::from_bytes(fs::read(image)?)?;
+            print_serialized(&atlantis::decode_profile(&image, product)?, json)?;
+            return Ok(());
+        }
+
+        Command::DecodeCapture { capture } => {
+            decode_capture(&fs::read(capture)?)?;
+            return Ok(());
+        }
+
+        _ => {}
+    }
+
+    let pid = args
+        .device
+        .as_deref()
+        .map(|pid| u16::from_str_radix(pid, 16))
+        .transpose()
+        .map_err(|_| "Invalid USB product ID".to_string())?;
+    let mut devices = Vec::new();
+    for (index, (device, product)) in lamzu::devices()?.into_iter().enumerate() {
+        let hid_info = device.get_device_info()?;
+        let path = hid_info.path().to_string_lossy().into_owned();
+        if pid.is_some_and(|pid| hid_info.product_id() != pid)
+            || args
+                .serial
+                .as_deref()
+                .is_some_and(|serial| hid_info.serial_number() != Some(serial))
+            || args.path.as_deref().is_some_and(|p| path != p)
+            || args.index.is_some_and(|i| index != i)
+        {
+            continue;
+        }
+        devices.push((index, device, product));
+    }
+
+    // Every selected device is opened with its own capture.
+    if args.capture.is_some() && devices.len() > 1 {
+        return Err(concat!(
+            "A capture file can only be saved for one device. Select one with ",
+            "`--index`, `--path` or `--serial`."
+        )
+        .into());
+    }
+
+    let mut devices = devices
+        .into_iter()
+        .map(|(index, device, product)| SelectedDevice::open(&args, index, device, product))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if let Command::List { json, .. } = args.command {
+        let list = group_mice(devices, args.force)
+            .into_iter()
+            .map(|connections| {
+                let connections = connections
+                    .into_iter()
+                    .map(|device| {
+                        Ok(ListedDevice {
+                            index: device.index,
+                            path: device.path,
+                            pid: format!("{:04x}", device.product_id),
+                            product: device.product,
+                            connection: device.product.connection(),
+                            info: device.mouse.device_info()?,
+                            interface: device.interface,
+                        })
+                    })
+                    .collect::<lamzu::Result<_>>()?;
+                Ok(ListedMouse { connections })
             })
-            .collect();
+            .collect::<lamzu::Result<Vec<_>>>()?;
         print_serialized(&list, json)?;
         return Ok(());
     }
 
-    let Some((device, product)) = devices.into_iter().next() else {
+    if devices.is_empty() {
         return Err("No compatible devices found.".into());
-    };
+    }
+
+    let all = matches!(
+        args.command,
+        Command::Get { all: true, .. }
+            | Command::Set { all: true, .. }
+            | Command::SetActive { all: true, .. }
+    );
+    if !all {
+        devices = vec![preferred_connection(devices, args.prefer)];
+    }
+
+    // Read the profile input once, since it may come from stdin.
+    if let Command::Set { file, config, .. } = &mut args.command {
+        *config = Some(get_file_arg_or_stdin(file.take(), config.take())?);
+    }
+
+    let count = devices.len();
+    let mut failures = 0;
+    for device in devices {
+        if all {
+            eprintln!("Device {} ({}):", device.index, device.product);
+        }
+        if let Err(e) = run(args.clone(), device.mouse) {
+            if !all {
+                return Err(e);
+            }
+            eprintln!("Error: {e}");
+            failures += 1;
+        }
+    }
+    if failures > 0 {
+        return Err(format!("{failures} of {count} devices failed").into());
+    }
+
+    Ok(())
+}
+
+/// A selected device, opened with its product's driver.
+struct SelectedDevice {
+    /// Index in the list of compatible devices, for `--index`.
+    index: usize,
+
+    path: String,
+    product_id: u16,
+    serial_number: Option<String>,
+    product: Product,
+
+    /// Configuration interface, only read for `list --verbose`.
+    interface: Option<ListedInterface>,
+
+    mouse: Box<dyn Mouse>,
+}
+
+impl SelectedDevice {
+    fn open(
+        args: &Cli,
+        index: usize,
+        device: HidDevice,
+        product: Product,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let hid_info = device.get_device_info()?;
+        let interface = if let Command::List { verbose: true, .. } = args.command {
+            Some(ListedInterface {
+                number: hid_info.interface_number(),
+                descriptor: descriptor::read(&device)?,
+            })
+        } else {
+            None
+        };
+        Ok(Self {
+            index,
+            path: hid_info.path().to_string_lossy().into_owned(),
+            product_id: hid_info.product_id(),
+            serial_number: hid_info.serial_number().map(str::to_string),
+            product,
+            interface,
+            mouse: product.open(transport(args, device)?, request_policy(args)),
+        })
+    }
+}
 
-    if product == Product::Unknown && !args.force {
+/// Wraps a device in the transport used for all requests, which logs and
+/// captures the reports exchanged if asked to.
+fn transport(args: &Cli, device: HidDevice) -> io::Result<Trace<HidDevice>> {
+    let mut transport = Trace::new(device);
+    if args.trace {
+        transport = transport.log_to(io::stderr());
+    }
+    if let Some(path) = &args.capture {
+        // Flush every line so the capture survives a failure part way through.
+        transport = transport.capture_to(LineWriter::new(File::create(path)?));
+    }
+    Ok(transport)
+}
+
+/// Groups devices that are likely connections to the same physical mouse,
+/// keeping them in order. This is only a guess, so it's only used for showing
+/// devices, not for choosing which ones to use.
+///
+/// Devices are only read if there are different kinds of connections to
+/// compare, and untested ones only if forced. A device that can't be
+/// identified, e.g. because it's asleep, is kept separate.
+fn group_mice(devices: Vec<SelectedDevice>, force: bool) -> Vec<Vec<SelectedDevice>> {
+    let connections: BTreeSet<_> = devices
+        .iter()
+        .map(|device| device.product.connection())
+        .collect();
+    let mut mice: Vec<Vec<(Option<MouseIdentity>, SelectedDevice)>> = Vec::new();
+    for device in devices {
+        let identity = if connections.len() > 1 && check_tested(device.product, force).is_ok() {
+            identify_mouse(&device)
+        } else {
+            None
+        };
+        let mouse = identity.as_ref().and_then(|identity| {
+            mice.iter().position(|connections| {
+                connections.iter().all(|(other, _)| {
+                    other
+                        .as_ref()
+                        .is_some_and(|other| identity.is_same_mouse(other))
+                })
+            })
+        });
+        match mouse {
+            Some(mouse) => mice[mouse].push((identity, device)),
+            None => mice.push(vec![(identity, device)]),
+        }
+    }
+    mice.into_iter()
+        .map(|connections| connections.into_iter().map(|(_, device)| device).collect())
+        .collect()
+}
+
+/// Reads what identifies the mouse behind a device, logging any failure.
+fn identify_mouse(device: &SelectedDevice) -> Option<MouseIdentity> {
+    device
+        .mouse
+        .identity()
+        .inspect_err(|e| {
+            eprintln!(
+                "Couldn't compare device {} ({}) with other connections: {e}",
+                device.index, device.path
+            )
+        })
+        .ok()
+}
+
+/// Picks the first device with the preferred kind of connection, or else the
+/// first device.
+fn preferred_connection(mut devices: Vec<SelectedDevice>, prefer: Prefer) -> SelectedDevice {
+    let preferred = devices
+        .iter()
+        .position(|device| device.product.connection() == prefer.into())
+        .unwrap_or(0);
+    devices.swap_remove(preferred)
+}
+
+/// Refuses untested products unless forced.
+fn check_tested(product: Product, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if !product.is_tested() && !force {
         return Err(concat!(
             "The connected device has not been tested with this tool. Use the",
             "`--force` flag to enable untested devices at your own risk."
         )
         .into());
     }
+    Ok(())
+}
 
-    let atlantis = Atlantis::connect(device)?;
+fn request_policy(args: &Cli) -> RequestPolicy {
+    let mut policy = if let Command::Probe { .. } = args.command {
+        // Most probes get no response, so don't wait long for each.
+        PROBE_REQUEST_POLICY
+    } else {
+        RequestPolicy::default()
+    };
+    if let Some(timeout) = args.timeout {
+        policy.timeout = Duration::from_millis(timeout);
+    }
+    if let Some(retries) = args.retries {
+        policy.retries = retries;
+    }
+    policy
+}
 
-    eprintln!("You may need to move your mouse to wake it up...");
+/// Runs a command that needs a mouse on one device.
+fn run(args: Cli, mouse: Box<dyn Mouse>) -> Result<(), Box<dyn std::error::Error>> {
+    check_tested(mouse.product(), args.force)?;
 
+    // Capabilities are known from the product alone, and device information
+    // comes from the USB descriptor, so neither needs the mouse to be awake.
     match args.command {
-        Command::Get { json, profile } => {
+        Command::Capabilities { json } => {
+            print_serialized(&mouse.capabilities(), json)?;
+            return Ok(());
+        }
+        Command::Info { json } => {
+            print_serialized(&mouse.device_info()?, json)?;
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // Watching shouldn't wait for a sleeping mouse, it just checks again later.
+    if !matches!(args.command, Command::GetBattery { watch: true, .. }) {
+        wait_until_awake(&*mouse)?;
+    }
+
+    match args.command {
+        Command::Get { json, profile, .. } => {
             if let Some(profile_number) = profile {
                 // Profiles numbered from 1 for CLI.
-                let profile = atlantis.profile(profile_number.saturating_sub(1))?;
+                let profile = mouse.profile(profile_number.saturating_sub(1))?;
                 eprintln!("Profile {} retrieved from mouse:", profile_number);
                 print_serialized(&profile, json)?;
             } else {
-                let profiles = atlantis.profiles()?;
+                let profiles = mouse.profiles()?;
                 eprintln!("All profiles retrieved from mouse:");
                 print_serialized(&profiles, json)?;
             }
@@ -138,10 +713,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             profile,
             file,
             config,
+            ..
         } => {
             // Test read for potentially untested devices to hopefully pick up any errors.
             if args.force {
-                atlantis.profile(0)?;
+                mouse.profile(0)?;
             }
 
             let input = get_file_arg_or_stdin(file, config)?;
@@ -149,46 +725,193 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 let profile: Profile = if json {
                     serde_json::from_str(&input)?
                 } else {
-                    ron::de::from_str(&input).unwrap()
+                    ron::de::from_str(&input)?
                 };
 
                 // Profiles numbered from 1 for CLI.
-                atlantis.set_profile(profile_number.saturating_sub(1), &profile)?;
-                eprintln!("Profile {} configured", profile_number);
+                let summary = mouse.set_profile(profile_number.saturating_sub(1), &profile)?;
+                eprintln!(
+                    "Profile {} configured ({} bytes written in {} reports)",
+                    profile_number, summary.bytes, summary.reports
+                );
             } else {
                 let profiles: Vec<Profile> = if json {
                     serde_json::from_str(&input)?
                 } else {
-                    ron::de::from_str(&input).unwrap()
+                    ron::de::from_str(&input)?
                 };
-                atlantis.set_profiles(&profiles)?;
-                eprintln!("Profiles configured");
+                let summary = mouse.set_profiles(&profiles)?;
+                eprintln!(
+                    "Profiles configured ({} bytes written in {} reports)",
+                    summary.bytes, summary.reports
+                );
+            }
+        }
+
+        Command::Reset { profile, yes, .. } => {
+            let question = if let Some(profile_number) = profile {
+                format!("Reset profile {profile_number} to default settings?")
+            } else {
+                "Reset all profiles to default settings?".to_string()
+            };
+            if !yes && !confirm(&question)? {
+                return Ok(());
+            }
+
+            if let Some(profile_number) = profile {
+                // Profiles numbered from 1 for CLI.
+                let summary = mouse.reset_profile(profile_number.saturating_sub(1))?;
+                eprintln!(
+                    "Profile {} reset ({} bytes written in {} reports)",
+                    profile_number, summary.bytes, summary.reports
+                );
+            } else {
+                let summary = mouse.factory_reset()?;
+                eprintln!(
+                    "All profiles reset ({} bytes written in {} reports)",
+                    summary.bytes, summary.reports
+                );
             }
         }
 
         Command::GetActive => {
             // Profiles numbered from 1 for CLI.
-            let profile_number = atlantis.active_profile()? + 1;
+            let profile_number = mouse.active_profile()? + 1;
             eprintln!("Active profile on mouse:");
             println!("{}", profile_number);
         }
 
-        Command::SetActive { profile_number } => {
+        Command::SetActive { profile_number, .. } => {
             // Profiles numbered from 1 for CLI.
-            atlantis.set_active_profile(profile_number.saturating_sub(1))?;
+            mouse.set_active_profile(profile_number.saturating_sub(1))?;
             eprintln!("Set active profile to:");
             println!("{}", profile_number);
         }
 
-        Command::GetBattery { millivolts } => {
-            if millivolts {
-                println!("{}", atlantis.battery_voltage()?);
+        Command::GetBattery {
+            millivolts,
+            status,
+            json,
+            watch,
+            interval,
+            history,
+            thresholds,
+            exec,
+            notify,
+        } => {
+            if watch {
+                watch_battery(
+                    &*mouse,
+                    &BatteryWatch {
+                        interval: Duration::from_secs(interval),
+                        history,
+                        thresholds,
+                        exec,
+                        notify,
+                    },
+                )?;
+            } else if status {
+                print_serialized(&mouse.battery_status()?, json)?;
+            } else if millivolts {
+                println!("{}", mouse.battery_voltage()?);
             } else {
-                println!("{}", atlantis.battery_percentage()?);
+                println!("{}", mouse.battery_percentage()?);
             }
         }
 
-        Command::List { .. } => {
+        Command::DumpFlash { profile, output } => {
+            let atlantis = atlantis_driver(&*mouse)?;
+            let images = if let Some(profile_number) = profile {
+                // Profiles numbered from 1 for CLI.
+                vec![atlantis.read_flash_image(profile_number.saturating_sub(1))?]
+            } else {
+                (0..mouse.num_profiles())
+                    .map(|i| atlantis.read_flash_image(i))
+                    .collect::<Result<_, _>>()?
+            };
+            let data: Vec<u8> = images
+                .into_iter()
+                .flat_map(FlashImage::into_bytes)
+                .collect();
+            fs::write(&output, data)?;
+            eprintln!("Flash dumped to {}", output.display());
+        }
+
+        Command::RestoreFlash { profile, input } => {
+            let atlantis = atlantis_driver(&*mouse)?;
+            let data = fs::read(input)?;
+            let profile_indices: Vec<_> = if let Some(profile_number) = profile {
+                // Profiles numbered from 1 for CLI.
+                vec![profile_number.saturating_sub(1)]
+            } else {
+                (0..mouse.num_profiles()).collect()
+            };
+            if data.len() != profile_indices.len() * atlantis::PROFILE_FLASH_LEN {
+                return Err(format!(
+                    "Flash dump is {} bytes, expected {} for {} profile(s)",
+                    data.len(),
+                    profile_indices.len() * atlantis::PROFILE_FLASH_LEN,
+                    profile_indices.len()
+                )
+                .into());
+            }
+
+            for (i, chunk) in profile_indices
+                .into_iter()
+                .zip(data.chunks(atlantis::PROFILE_FLASH_LEN))
+            {
+                let image = FlashImage::from_bytes(chunk.to_vec())?;
+                let summary = atlantis.write_flash_image(i, &image)?;
+                eprintln!(
+                    "Profile {} restored ({} bytes written in {} reports)",
+                    i + 1,
+                    summary.bytes,
+                    summary.reports
+                );
+            }
+        }
+
+        Command::Monitor => {
+            let atlantis = atlantis_driver(&*mouse)?;
+            for event in atlantis.events() {
+                match event {
+                    Ok(event) => println!("{}", serde_json::to_string(&event)?),
+                    // A garbled report shouldn't end the session.
+                    Err(
+                        e @ (lamzu::Error::BadChecksum(_)
+                        | lamzu::Error::MalformedReport(_)
+                        | lamzu::Error::ShortRead { .. }),
+                    ) => eprintln!("Skipping corrupt report: {e}"),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        Command::Probe {
+            json,
+            odd_commands,
+            yes,
+        } => {
+            if !yes
+                && !confirm(concat!(
+                    "Probing sends unknown commands to the mouse, which could change its ",
+                    "settings or worse. Back up your flash with `dump-flash` first. Continue?"
+                ))?
+            {
+                return Ok(());
+            }
+            let atlantis = atlantis_driver(&*mouse)?;
+            eprintln!("Probing mouse, this may take a few minutes...");
+            print_serialized(&atlantis.probe(odd_commands)?, json)?;
+        }
+
+        Command::List { .. }
+        | Command::Capabilities { .. }
+        | Command::Info { .. }
+        | Command::Daemon { .. }
+        | Command::Encode { .. }
+        | Command::Decode { .. }
+        | Command::DecodeCapture { .. } => {
             unreachable!("I don't know how you got here...");
         }
     }
@@ -196,6 +919,367 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Returns the Atlantis driver behind a mouse, for commands that are specific
+/// to its protocol.
+fn atlantis_driver<'a>(
+    mouse: &'a (dyn Mouse + 'static),
+) -> Result<&'a Atlantis<Box<dyn Transport>>, Box<dyn std::error::Error>> {
+    mouse
+        .as_any()
+        .downcast_ref()
+        .ok_or_else(|| format!("This command isn't supported for the {}", mouse.product()).into())
+}
+
+/// Waits for the mouse to respond, asking the user to wake it up if it appears
+/// to be asleep.
+fn wait_until_awake(mouse: &dyn Mouse) -> lamzu::Result<()> {
+    let start = Instant::now();
+    let mut prompted = false;
+    loop {
+        match mouse.active_profile() {
+            Err(lamzu::Error::DeviceAsleep) if start.elapsed() < WAKE_TIMEOUT => {
+                if !prompted {
+                    eprintln!("Mouse appears to be asleep. Move it to wake it up...");
+                    prompted = true;
+                }
+            }
+            result => return result.map(|_| ()),
+        }
+    }
+}
+
+/// Prints the decoded reports in a USB capture, annotated with the settings
+/// they access, followed by a summary of anything unknown.
+fn decode_capture(data: &[u8]) -> lamzu::Result<()> {
+    let mut unknown_commands = BTreeSet::new();
+    let mut unknown_addresses: Vec<Range<usize>> = Vec::new();
+    for record in atlantis::read_pcap(data)? {
+        let annotation = atlantis::annotate_report(&record.report);
+        let mut notes = annotation.fields;
+        if let Some(cmd) = annotation.unknown_command {
+            notes.push("UNKNOWN COMMAND".to_string());
+            unknown_commands.insert(cmd);
+        }
+        for range in annotation.unknown_addresses {
+            notes.push(format!(
+                "UNKNOWN ADDRESS {:#06x}..{:#06x}",
+                range.start, range.end
+            ));
+            unknown_addresses.push(range);
+        }
+
+        let arrow = match record.direction {
+            Direction::Out => "->",
+            Direction::In => "<-",
+        };
+        let notes = if notes.is_empty() {
+            String::new()
+        } else {
+            format!("  # {}", notes.join(", "))
+        };
+        println!(
+            "[{:>10.3} ms] {arrow} {}{notes}",
+            record.time_us as f64 / 1000.0,
+            atlantis::describe_report(&record.report)
+        );
+    }
+
+    if !unknown_commands.is_empty() {
+        let commands: Vec<_> = unknown_commands
+            .iter()
+            .map(|cmd| format!("{cmd:#04x}"))
+            .collect();
+        eprintln!("Unknown commands: {}", commands.join(", "));
+    }
+    if !unknown_addresses.is_empty() {
+        // Merge overlapping ranges from requests and their responses.
+        unknown_addresses.sort_by_key(|range| range.start);
+        let mut merged: Vec<Range<usize>> = Vec::new();
+        for range in unknown_addresses {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        let addresses: Vec<_> = merged
+            .iter()
+            .map(|range| format!("{:#06x}..{:#06x}", range.start, range.end))
+            .collect();
+        eprintln!("Unknown flash addresses: {}", addresses.join(", "));
+    }
+    Ok(())
+}
+
+/// Options for watching the battery.
+struct BatteryWatch {
+    interval: Duration,
+    history: Option<PathBuf>,
+    thresholds: Vec<u8>,
+    exec: Option<String>,
+    notify: bool,
+}
+
+/// Adds a battery check to those kept in memory, dropping the oldest ones.
+fn push_battery_sample(samples: &mut VecDeque<BatterySample>, sample: BatterySample) {
+    samples.push_back(sample);
+    if samples.len() > MAX_BATTERY_SAMPLES {
+        samples.pop_front();
+    }
+}
+
+/// Checks the battery forever, logging each check and running hooks when the
+/// charge drops to a threshold.
+fn watch_battery(
+    mouse: &dyn Mouse,
+    watch: &BatteryWatch,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut samples: VecDeque<BatterySample> = VecDeque::new();
+    let mut history_file = None;
+    if let Some(path) = &watch.history {
+        if path.exists() {
+            // Skip lines that can't be parsed rather than refusing to start.
+            for line in BufReader::new(File::open(path)?).lines() {
+                if let Ok(sample) = serde_json::from_str(&line?) {
+                    push_battery_sample(&mut samples, sample);
+                }
+            }
+        }
+        history_file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+    }
+
+    loop {
+        match mouse.battery_status() {
+            Ok(status) => {
+                let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                let sample = BatterySample::new(time, &status);
+                let previous = samples.back().map(|s| s.percentage);
+                push_battery_sample(&mut samples, sample);
+                if let Some(file) = &mut history_file {
+                    writeln!(file, "{}", serde_json::to_string(&sample)?)?;
+                }
+
+                let runtime = battery::estimate_runtime(samples.make_contiguous());
+                let remaining = runtime.map_or(String::new(), |runtime| {
+                    format!(", about {} remaining", format_duration(runtime))
+                });
+                println!(
+                    "{}% ({} mV, {:?}){}",
+                    status.percentage, status.millivolts, status.charge_state, remaining
+                );
+
+                // Only the lowest threshold reached runs the hooks.
+                let threshold = watch
+                    .thresholds
+                    .iter()
+                    .filter(|t| status.percentage <= **t && previous.is_none_or(|p| p > **t))
+                    .min();
+                if let Some(threshold) = threshold {
+                    run_battery_hooks(watch, &status, *threshold, runtime);
+                }
+            }
+            Err(lamzu::Error::DeviceAsleep | lamzu::Error::Timeout) => {
+                eprintln!("Mouse is not responding, will check again later");
+            }
+            Err(e) => return Err(e.into()),
+        }
+        thread::sleep(watch.interval);
+    }
+}
+
+fn run_battery_hooks(
+    watch: &BatteryWatch,
+    status: &BatteryStatus,
+    threshold: u8,
+    runtime: Option<Duration>,
+) {
+    if let Some(exec) = &watch.exec {
+        let mut command = process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg(exec)
+            .env("LAMZU_BATTERY_PERCENTAGE", status.percentage.to_string())
+            .env("LAMZU_BATTERY_MILLIVOLTS", status.millivolts.to_string())
+            .env("LAMZU_BATTERY_THRESHOLD", threshold.to_string());
+        if let Some(runtime) = runtime {
+            command.env(
+                "LAMZU_BATTERY_REMAINING_SECS",
+                runtime.as_secs().to_string(),
+            );
+        }
+        if let Err(e) = command.status() {
+            eprintln!("Failed to run battery hook: {e}");
+        }
+    }
+    if watch.notify {
+        let body = match runtime {
+            Some(runtime) => format!(
+                "{}% remaining (about {})",
+                status.percentage,
+                format_duration(runtime)
+            ),
+            None => format!("{}% remaining", status.percentage),
+        };
+        let result = process::Command::new("notify-send")
+            .arg("Mouse battery low")
+            .arg(body)
+            .status();
+        if let Err(e) = result {
+            eprintln!("Failed to show notification: {e}");
+        }
+    }
+}
+
+/// Formats a duration in hours and minutes, e.g. `3h 05m`.
+fn format_duration(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    format!("{}h {:02}m", minutes / 60, minutes % 60)
+}
+
+/// Parses a product by the name it's serialized with, e.g. `AtlantisWired`.
+fn parse_product(name: &str) -> Result<Product, String> {
+    serde_json::from_value(serde_json::Value::String(name.to_string()))
+        .map_err(|_| format!("Unknown product '{name}'"))
+}
+
+/// Asks the user a yes / no question on stderr, defaulting to no.
+fn confirm(question: &str) -> io::Result<bool> {
+    eprint!("{question} [y/N] ");
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Settings applied by the daemon to mice as they are connected.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DaemonConfig {
+    mice: Vec<DaemonMouse>,
+}
+
+/// Settings for the mice that match all of the given fields. The first match
+/// is used.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DaemonMouse {
+    /// USB serial number of the mouse or its receiver.
+    #[serde(default)]
+    serial: Option<String>,
+
+    #[serde(default)]
+    product: Option<Product>,
+
+    /// Profiles to write, starting from the first.
+    #[serde(default)]
+    profiles: Vec<Profile>,
+
+    /// Profile number to activate.
+    #[serde(default)]
+    active_profile: Option<usize>,
+}
+
+impl DaemonMouse {
+    fn matches(&self, device: &SelectedDevice) -> bool {
+        let serial_matches = self
+            .serial
+            .as_deref()
+            .is_none_or(|serial| device.serial_number.as_deref() == Some(serial));
+        serial_matches && self.product.is_none_or(|p| p == device.product)
+    }
+}
+
+/// Applies the configured settings to mice as they are connected, including
+/// those connected at startup. Runs until the monitor fails.
+fn run_daemon(
+    args: &Cli,
+    config: &DaemonConfig,
+    mut monitor: Monitor,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if monitor.is_polling() {
+        eprintln!("Polling for mice...");
+    } else {
+        eprintln!("Waiting for mice to be connected...");
+    }
+
+    // Paths of the devices connected last time, and of those that couldn't be
+    // set up and should be tried again. Every connection is set up on its own,
+    // since telling which ones lead to the same mouse is only a guess. Writing
+    // the same settings again through another connection doesn't change
+    // anything.
+    let mut known_paths = BTreeSet::new();
+    let mut failed_paths = BTreeSet::new();
+    loop {
+        let mut devices = Vec::new();
+        for (index, (device, product)) in lamzu::devices()?.into_iter().enumerate() {
+            // Skip devices that were disconnected while listing them.
+            if let Ok(device) = SelectedDevice::open(args, index, device, product) {
+                devices.push(device);
+            }
+        }
+        let paths: BTreeSet<String> = devices.iter().map(|d| d.path.clone()).collect();
+        for path in known_paths.difference(&paths) {
+            eprintln!("Disconnected: {path}");
+        }
+        failed_paths.retain(|path| paths.contains(path));
+
+        for device in devices {
+            let new = !known_paths.contains(&device.path);
+            if !new && !failed_paths.contains(&device.path) {
+                continue;
+            }
+
+            eprintln!(
+                "{}: {} ({})",
+                if new { "Connected" } else { "Retrying" },
+                device.product,
+                device.path
+            );
+            let Some(settings) = config.mice.iter().find(|mouse| mouse.matches(&device)) else {
+                continue;
+            };
+            if let Err(e) = apply_daemon_settings(args, settings, &*device.mouse) {
+                eprintln!("Error: {e}");
+                eprintln!(
+                    "Trying again in {} seconds",
+                    DAEMON_RETRY_INTERVAL.as_secs()
+                );
+                failed_paths.insert(device.path);
+            } else {
+                failed_paths.remove(&device.path);
+            }
+        }
+
+        known_paths = paths;
+        if failed_paths.is_empty() {
+            monitor.wait()?;
+        } else {
+            monitor.wait_timeout(DAEMON_RETRY_INTERVAL)?;
+        }
+    }
+}
+
+fn apply_daemon_settings(
+    args: &Cli,
+    settings: &DaemonMouse,
+    mouse: &dyn Mouse,
+) -> Result<(), Box<dyn std::error::Error>> {
+    check_tested(mouse.product(), args.force)?;
+    wait_until_awake(mouse)?;
+
+    if !settings.profiles.is_empty() {
+        let summary = mouse.set_profiles(&settings.profiles)?;
+        eprintln!(
+            "Profiles configured ({} bytes written in {} reports)",
+            summary.bytes, summary.reports
+        );
+    }
+    if let Some(profile_number) = settings.active_profile {
+        // Profiles numbered from 1 for CLI.
+        mouse.set_active_profile(profile_number.saturating_sub(1))?;
+        eprintln!("Set active profile to {profile_number}");
+    }
+    Ok(())
+}
+
 /// Returns input string from file, CLI argument, or from stdin, in that order.
 fn get_file_arg_or_stdin(file: Option<PathBuf>, arg: Option<String>) -> io::Result<String> {
     let profile_text = if let Some(file_path) = file {
@@ -228,8 +1312,30 @@ fn print_serialized<T: Serialize>(
     Ok(())
 }
 
+/// Physical mouse and each of its connections.
+#[derive(Serialize)]
+struct ListedMouse {
+    connections: Vec<ListedDevice>,
+}
+
 #[derive(Serialize)]
 struct ListedDevice {
+    /// Index for selecting the device with `--index`.
+    index: usize,
+
+    path: String,
     pid: String,
     product: Product,
+    connection: Connection,
+    info: DeviceInfo,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interface: Option<ListedInterface>,
+}
+
+/// Configuration interface of a listed device.
+#[derive(Serialize)]
+struct ListedInterface {
+    number: i32,
+    descriptor: ReportDescriptor,
 }
@@ -0,0 +1,42 @@
+use crate::device::{find_devices, identify};
+use crate::{Mouse, Product, RequestPolicy};
+use hidapi::{HidApi, HidDevice};
+
+/// Identifies a device and opens it with the driver for its product.
+///
+/// Fails with [`Incompatible`](crate::Error::Incompatible) for devices that
+/// aren't the configuration interface of a known mouse. Untested products are
+/// opened too, so check [`Product::is_tested`] before writing to them.
+pub fn open(device: HidDevice) -> crate::Result<Box<dyn Mouse>> {
+    let product = identify(&device)?.ok_or(crate::Error::Incompatible)?;
+    Ok(product.open(device, RequestPolicy::default()))
+}
+
+/// Finds and opens mice with the right driver, keeping the HID API handle
+/// around for applications that look for devices repeatedly.
+pub struct DeviceManager {
+    api: HidApi,
+}
+
+impl DeviceManager {
+    pub fn new() -> crate::Result<Self> {
+        Ok(Self {
+            api: HidApi::new()?,
+        })
+    }
+
+    /// Lists compatible devices with their detected products, in order of
+    /// connection priority.
+    pub fn devices(&mut self) -> crate::Result<Vec<(HidDevice, Product)>> {
+        Ok(find_devices(&mut self.api, None)?)
+    }
+
+    /// Opens every compatible device, in order of connection priority.
+    pub fn open_all(&mut self) -> crate::Result<Vec<Box<dyn Mouse>>> {
+        Ok(self
+            .devices()?
+            .into_iter()
+            .map(|(device, product)| product.open(device, RequestPolicy::default()))
+            .collect())
+    }
+}
@@ -0,0 +1,100 @@
+use hidapi::HidDevice;
+use std::time::Duration;
+
+/// Blocking transport for exchanging raw HID reports with a device.
+///
+/// Device drivers are generic over this trait so the protocol can run against
+/// something other than a live USB HID device (e.g. an emulated mouse).
+pub trait Transport {
+    /// Writes a single report (including the report ID) and returns the number
+    /// of bytes written.
+    fn write(&self, report: &[u8]) -> crate::Result<usize>;
+
+    /// Reads a single report into `buf`, waiting for at most `timeout` (or
+    /// forever if `None`). Returns the number of bytes read, or 0 on timeout.
+    fn read_timeout(&self, buf: &mut [u8], timeout: Option<Duration>) -> crate::Result<usize>;
+
+    /// Returns information from the USB device descriptor, if the transport is
+    /// backed by a USB device.
+    fn usb_info(&self) -> crate::Result<Option<UsbInfo>> {
+        Ok(None)
+    }
+}
+
+/// Information from a USB device's descriptor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UsbInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+
+    /// Device release number (`bcdDevice`) in binary coded decimal.
+    pub release_number: u16,
+
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+impl UsbInfo {
+    /// Formats the device release number as a version, e.g. `1.02`.
+    pub fn release(&self) -> String {
+        format!(
+            "{:x}.{:02x}",
+            self.release_number >> 8,
+            self.release_number & 0xFF
+        )
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for &T {
+    fn write(&self, report: &[u8]) -> crate::Result<usize> {
+        (**self).write(report)
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout: Option<Duration>) -> crate::Result<usize> {
+        (**self).read_timeout(buf, timeout)
+    }
+
+    fn usb_info(&self) -> crate::Result<Option<UsbInfo>> {
+        (**self).usb_info()
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn write(&self, report: &[u8]) -> crate::Result<usize> {
+        (**self).write(report)
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout: Option<Duration>) -> crate::Result<usize> {
+        (**self).read_timeout(buf, timeout)
+    }
+
+    fn usb_info(&self) -> crate::Result<Option<UsbInfo>> {
+        (**self).usb_info()
+    }
+}
+
+impl Transport for HidDevice {
+    fn write(&self, report: &[u8]) -> crate::Result<usize> {
+        Ok(HidDevice::write(self, report)?)
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout: Option<Duration>) -> crate::Result<usize> {
+        let timeout_ms = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+        Ok(HidDevice::read_timeout(self, buf, timeout_ms)?)
+    }
+
+    fn usb_info(&self) -> crate::Result<Option<UsbInfo>> {
+        let info = self.get_device_info()?;
+        // Empty strings mean the descriptor doesn't have them.
+        let string = |s: Option<&str>| s.filter(|s| !s.is_empty()).map(str::to_string);
+        Ok(Some(UsbInfo {
+            vendor_id: info.vendor_id(),
+            product_id: info.product_id(),
+            release_number: info.release_number(),
+            manufacturer: string(info.manufacturer_string()),
+            product: string(info.product_string()),
+            serial_number: string(info.serial_number()),
+        }))
+    }
+}
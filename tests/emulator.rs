@@ -0,0 +1,94 @@
+use lamzu::atlantis::default_profile;
+use lamzu::profile::Resolution;
+use lamzu::{Atlantis, Emulator, Mouse, Product, Profile};
+
+const PRODUCT: Product = Product::AtlantisWireless4K;
+
+/// Compares profiles by their serialized form, since they can't be compared
+/// directly.
+fn assert_same_profile(a: &Profile, b: &Profile) {
+    assert_eq!(
+        serde_json::to_value(a).unwrap(),
+        serde_json::to_value(b).unwrap()
+    );
+}
+
+fn custom_profile() -> Profile {
+    let mut profile = default_profile(PRODUCT).unwrap();
+    profile.poll_rate = Some(4000);
+    profile.debounce_ms = Some(2);
+    profile.motion_sync = Some(true);
+    profile.resolutions = vec![Resolution::new(800, 800), Resolution::new(26000, 13000)];
+    profile.resolution_colors.truncate(2);
+    profile.current_resolution_index = Some(0);
+    profile
+}
+
+#[test]
+fn reset_profile_writes_default_profile() {
+    let emulator = Emulator::new();
+    let mouse = Atlantis::new(&emulator, PRODUCT);
+    mouse.reset_profile(1).unwrap();
+
+    assert_same_profile(
+        &mouse.profile(1).unwrap(),
+        &default_profile(PRODUCT).unwrap(),
+    );
+    assert_eq!(emulator.active_profile(), 0);
+    assert!(emulator.flash(0).iter().all(|&byte| byte == 0));
+}
+
+#[test]
+fn set_profile_round_trips() {
+    let emulator = Emulator::new();
+    let mouse = Atlantis::new(&emulator, PRODUCT);
+    let profile = custom_profile();
+    mouse.set_profile(2, &profile).unwrap();
+
+    assert_same_profile(&mouse.profile(2).unwrap(), &profile);
+    assert_eq!(emulator.active_profile(), 0);
+}
+
+#[test]
+fn repeated_write_sends_nothing() {
+    let emulator = Emulator::new();
+    let mouse = Atlantis::new(&emulator, PRODUCT);
+    let profile = custom_profile();
+    let first = mouse.set_profile(0, &profile).unwrap();
+    assert!(first.bytes > 0 && first.reports > 0, "{first:?}");
+
+    let second = mouse.set_profile(0, &profile).unwrap();
+    assert_eq!((second.bytes, second.reports), (0, 0));
+}
+
+#[test]
+fn partial_profile_only_changes_its_fields() {
+    let emulator = Emulator::new();
+    let mouse = Atlantis::new(&emulator, PRODUCT);
+    mouse.reset_profile(0).unwrap();
+
+    let partial = Profile {
+        poll_rate: Some(500),
+        ..Profile::default()
+    };
+    let summary = mouse.set_profile(0, &partial).unwrap();
+    assert_eq!(summary.reports, 1);
+
+    let mut expected = default_profile(PRODUCT).unwrap();
+    expected.poll_rate = Some(500);
+    assert_same_profile(&mouse.profile(0).unwrap(), &expected);
+}
+
+#[test]
+fn factory_reset_activates_first_profile() {
+    let emulator = Emulator::new();
+    let mouse = Atlantis::new(&emulator, PRODUCT);
+    mouse.set_active_profile(3).unwrap();
+    mouse.factory_reset().unwrap();
+
+    assert_eq!(mouse.active_profile().unwrap(), 0);
+    let default = default_profile(PRODUCT).unwrap();
+    for profile in mouse.profiles().unwrap() {
+        assert_same_profile(&profile, &default);
+    }
+}
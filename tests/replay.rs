@@ -0,0 +1,56 @@
+use lamzu::atlantis::Replay;
+use lamzu::{Atlantis, Mouse, Product, Profile};
+use std::fs::File;
+use std::io::BufReader;
+
+const PRODUCT: Product = Product::AtlantisWireless4K;
+
+/// Recorded with `Trace` from the emulator, with profile 1 reset, while reading
+/// the active profile and profile 1, setting its poll rate to 500 Hz and
+/// reading the battery voltage.
+const CAPTURE: &str = "tests/captures/emulator-get-set.jsonl";
+
+fn replay() -> Replay {
+    Replay::from_reader(BufReader::new(File::open(CAPTURE).unwrap())).unwrap()
+}
+
+#[test]
+fn replays_capture() {
+    let replay = replay();
+    let mouse = Atlantis::new(&replay, PRODUCT);
+
+    assert_eq!(mouse.active_profile().unwrap(), 0);
+    assert_eq!(mouse.profile(0).unwrap().poll_rate, Some(1000));
+    let partial = Profile {
+        poll_rate: Some(500),
+        ..Profile::default()
+    };
+    let summary = mouse.set_profile(0, &partial).unwrap();
+    assert_eq!((summary.bytes, summary.reports), (2, 1));
+    assert_eq!(mouse.battery_voltage().unwrap(), 3900);
+
+    replay.finish().unwrap();
+}
+
+#[test]
+fn rejects_different_request() {
+    let replay = replay();
+    let mouse = Atlantis::new(&replay, PRODUCT);
+
+    assert_eq!(mouse.active_profile().unwrap(), 0);
+    let result = mouse.set_active_profile(1);
+    assert!(
+        matches!(result, Err(lamzu::Error::ReplayMismatch { .. })),
+        "{result:?}"
+    );
+}
+
+#[test]
+fn finish_rejects_unreplayed_reports() {
+    let replay = replay();
+    let mouse = Atlantis::new(&replay, PRODUCT);
+
+    assert_eq!(mouse.active_profile().unwrap(), 0);
+    assert!(replay.remaining() > 0);
+    assert!(replay.finish().is_err());
+}